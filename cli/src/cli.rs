@@ -1,11 +1,11 @@
 use std::path::PathBuf;
 
-use clap::{builder::ValueParser, value_parser, ArgAction, Parser, Subcommand};
+use clap::{builder::ValueParser, value_parser, ArgAction, Args, Parser, Subcommand};
 use stemgen::{
-    constant::{DEFAULT_MODEL, STEM_DEFAULT_COLOR, STEM_DEFAULT_LABEL}, demucs::{Device, Model}, nistem::{Codec, Color, SampleRate}
+    audiofile::{AudioFormat, BitDepth}, constant::{DEFAULT_MODEL, STEM_DEFAULT_COLOR, STEM_DEFAULT_LABEL}, cover::{CoverOptions, ImageFormat, PictureType}, demucs::{Device, Model}, multitrack::OutputFormat, nistem::{Codec, Color, EncoderOptions, OpusApplication, SampleRate}, verify::DEFAULT_THRESHOLD
 };
 
-use crate::constants::*;
+use crate::{constants::*, template::NameTemplate};
 
 fn parse_color(value: &str) -> Result<Color, String> {
     value.try_into()
@@ -19,6 +19,56 @@ fn parse_samplerate(value: &str) -> Result<SampleRate, String> {
     value.try_into()
 }
 
+fn parse_opus_application(value: &str) -> Result<OpusApplication, String> {
+    value.try_into()
+}
+
+/// Accept a bitrate in bits per second, or in kbit/s with a `k` suffix
+fn parse_bitrate(value: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match value.strip_suffix(['k', 'K']) {
+        Some(digits) => (digits, 1000),
+        None => (value, 1),
+    };
+    digits
+        .parse::<usize>()
+        .map(|bitrate| bitrate * multiplier)
+        .map_err(|_| format!("invalid bitrate {value}, expected a value such as 256k or 256000"))
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    let upper = value.to_ascii_uppercase();
+    let (digits, multiplier) = [('K', 1 << 10), ('M', 1 << 20), ('G', 1 << 30)]
+        .into_iter()
+        .find_map(|(unit, multiplier)| upper.strip_suffix(unit).map(|digits| (digits, multiplier)))
+        .unwrap_or((upper.as_str(), 1));
+    digits
+        .parse::<usize>()
+        .ok()
+        .filter(|size| *size > 0)
+        .map(|size| size * multiplier)
+        .ok_or(format!("invalid size {value}, expected a value such as 512M or 2G"))
+}
+
+fn parse_image_format(value: &str) -> Result<ImageFormat, String> {
+    value.try_into()
+}
+
+fn parse_picture_type(value: &str) -> Result<PictureType, String> {
+    value.try_into()
+}
+
+fn parse_audio_format(value: &str) -> Result<AudioFormat, String> {
+    value.try_into()
+}
+
+fn parse_bit_depth(value: &str) -> Result<BitDepth, String> {
+    value.try_into()
+}
+
+fn parse_output_format(value: &str) -> Result<OutputFormat, String> {
+    value.try_into()
+}
+
 fn parse_device(value: &str) -> Result<Device, String> {
     value.try_into()
 }
@@ -27,6 +77,10 @@ fn parse_model(value: &str) -> Result<Model, String> {
     value.try_into()
 }
 
+fn parse_name_template(value: &str) -> Result<NameTemplate, String> {
+    value.try_into()
+}
+
 /// A fictional versioning CLI
 #[derive(Debug, Parser, Default)] // requires `derive` feature
 #[command(name = "stemgen")]
@@ -39,9 +93,11 @@ pub struct Cli {
     pub force: bool,
     #[arg(long, help = "Display verbose information which may be useful for debugging", default_value_t = false, action = ArgAction::SetTrue, global = true)]
     pub verbose: bool,
+    #[arg(long, help = "Apply a preset from the configuration files on top of their defaults", value_name = "NAME", global = true)]
+    pub preset: Option<String>,
     #[arg(short, long, help = "The codec to use for the stem stream stored in the output MP4", value_enum, value_parser = ValueParser::new(parse_codec), default_value = "aac", global = true)]
     pub codec: Codec,
-    #[arg(short, long, help = "The sample rate to use for the output", value_enum, value_parser = ValueParser::new(parse_samplerate), default_value = "44100", global = true)]
+    #[arg(short, long, help = "The sample rate to use for the output, such as 44100, 48000 or 96000. The closest rate supported by the stem codec is used otherwise", value_enum, value_parser = ValueParser::new(parse_samplerate), default_value = "44100", global = true)]
     pub sample_rate: SampleRate,
     #[arg(long, help = "Custom label for the drum stem (the first one)", value_name = "LABEL", default_value_t = STEM_DEFAULT_LABEL[0].to_owned(), global = true)]
     pub drum_stem_label: String,
@@ -61,6 +117,22 @@ pub struct Cli {
     pub vocal_stem_color: Color,
     #[arg(short, long, help = "Extension for the STEM file", value_name = "EXT", default_value_t = DEFAULT_EXT.to_owned(), global = true)]
     pub ext: String,
+    #[arg(long, help = "Measure the EBU R128 loudness and true peak of the master and each stem", default_value_t = false, action = ArgAction::SetTrue, global = true)]
+    pub loudness: bool,
+    #[arg(long, help = "Scale all stems by a common gain so the master reaches the given integrated loudness, less when its true peak would exceed -1 dBTP. The master is decoded an extra time to measure it first", value_name = "LUFS", allow_negative_numbers = true, global = true)]
+    pub normalize: Option<f64>,
+    #[arg(long, help = "Write ReplayGain tags computed from the loudness of the master", default_value_t = false, action = ArgAction::SetTrue, global = true)]
+    pub replaygain: bool,
+    #[arg(long, help = "Target bitrate of the stem streams, for AAC and Opus (e.g. 256k)", value_parser = ValueParser::new(parse_bitrate), global = true)]
+    pub bitrate: Option<usize>,
+    #[arg(long, help = "Variable bitrate quality of the AAC stem streams, between 0.1 and 2", global = true)]
+    pub quality: Option<f32>,
+    #[arg(long, help = "Whether to use variable bitrate for the Opus stem streams", value_name = "BOOL", global = true)]
+    pub vbr: Option<bool>,
+    #[arg(long, help = "Compression level of the stem streams, for FLAC (0-12), ALAC (0-2) and Opus (0-10)", value_name = "LEVEL", global = true)]
+    pub compression_level: Option<usize>,
+    #[arg(long, help = "Opus application mode: voip, audio or lowdelay", value_name = "MODE", value_parser = ValueParser::new(parse_opus_application), global = true)]
+    pub opus_application: Option<OpusApplication>,
 }
 
 impl From<&'_ Cli> for (ffmpeg_next::codec::Id, i32) {
@@ -69,6 +141,44 @@ impl From<&'_ Cli> for (ffmpeg_next::codec::Id, i32) {
     }
 }
 
+impl From<&'_ Cli> for EncoderOptions {
+    fn from(val: &'_ Cli) -> Self {
+        EncoderOptions {
+            bitrate: val.bitrate,
+            quality: val.quality,
+            vbr: val.vbr,
+            compression_level: val.compression_level,
+            application: val.opus_application,
+            // Chosen per command
+            bit_depth: None,
+        }
+    }
+}
+
+/// Cover art of the generated files, the one of the input being used by
+/// default, or `cover.jpg` or `folder.jpg` next to it.
+#[derive(Debug, Args, Default)]
+pub struct CoverArgs {
+    #[arg(long, value_name = "IMAGE", help = "JPEG or PNG image to use as cover art, instead of the one of the input")]
+    pub cover: Option<PathBuf>,
+    #[arg(long, value_name = "PIXELS", help = "Scale the cover art down to fit in this width and height")]
+    pub cover_max_size: Option<u32>,
+    #[arg(long, value_name = "FORMAT", help = "Re-encode the cover art as jpeg or png", value_parser = ValueParser::new(parse_image_format))]
+    pub cover_format: Option<ImageFormat>,
+    #[arg(long, value_name = "TYPE", help = "Picture type of the cover art: front, back, artist, band, illustration, logo or other", value_parser = ValueParser::new(parse_picture_type), default_value = "front")]
+    pub cover_type: PictureType,
+}
+
+impl From<&'_ CoverArgs> for CoverOptions {
+    fn from(val: &'_ CoverArgs) -> Self {
+        CoverOptions {
+            max_size: val.cover_max_size,
+            format: val.cover_format,
+            picture_type: val.cover_type,
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 pub struct CreateArgs {
     #[arg(required = true)]
@@ -85,14 +195,30 @@ pub struct CreateArgs {
     pub vocal: PathBuf,
     #[arg(long, default_value_t = true)]
     pub copy_id3tags_from_mastered: bool,
+    #[arg(long, value_name = "FORMAT", help = "Write a NI STEM file (stem), one file per stream (wav, flac or aiff) or a single 10 channels file (multichannel-wav or multichannel-aiff)", value_parser = ValueParser::new(parse_output_format), default_value = "stem")]
+    pub format: OutputFormat,
+    #[arg(long, value_name = "BITS", help = "The bit depth of ALAC and FLAC stems or of the audio files (16, 24, 32 for floating point or 32i for integers), the one of the inputs by default", value_parser = ValueParser::new(parse_bit_depth))]
+    pub bit_depth: Option<BitDepth>,
+    #[arg(long, value_name = "FILE", help = "JSON file with the stem labels, colours and mastering DSP to use, as printed by the manifest command", conflicts_with_all = ["drum_stem_label", "bass_stem_label", "other_stem_label", "vocal_stem_label", "drum_stem_color", "bass_stem_color", "other_stem_color", "vocal_stem_color"])]
+    pub manifest: Option<PathBuf>,
+    #[arg(long, help = "Check that the stems reconstruct the master once the NI STEM file is written", default_value_t = false, action = ArgAction::SetTrue)]
+    pub verify: bool,
+    #[arg(long, value_name = "DB", help = "Maximum RMS residual accepted by --verify, in dBFS", allow_negative_numbers = true, default_value_t = DEFAULT_THRESHOLD)]
+    pub verify_threshold: f64,
+    #[command(flatten)]
+    pub artwork: CoverArgs,
 }
 
 #[derive(Debug, Parser, Default)]
 pub struct GenerateArgs {
-    #[arg(num_args = 1.., value_name = "FILES", help = "path(s) to a file supported by the FFmpeg codec available on your machine. Advanced glob pattern can be used such as '~/Music/**/*.mp3'", required = true)]
+    #[arg(num_args = 1.., value_name = "FILES", help = "path(s) to a file supported by the FFmpeg codec available on your machine. Advanced glob pattern can be used such as '~/Music/**/*.mp3'", required_unless_present = "recursive")]
     pub files: Vec<String>,
     #[arg(value_name = "OUTPUT", help = "path to an existing directory where to store the generated STEM file(s)", value_parser = value_parser!(PathBuf), required = true)]
     pub output: PathBuf,
+    #[arg(long, value_name = "DIR", help = "Process every audio file under this directory, recreating its sub-directories in OUTPUT", value_parser = value_parser!(PathBuf), conflicts_with = "files")]
+    pub recursive: Option<PathBuf>,
+    #[arg(long, value_name = "TEMPLATE", help = "Name the outputs after the tags of their input, such as '{artist}/{release}/{trackno:02} - {title}', each '/' adding a sub-directory", value_parser = ValueParser::new(parse_name_template))]
+    pub name_template: Option<NameTemplate>,
     #[arg(long, value_name = "DEVICE", help = "Device for the demucs model inference", value_parser = ValueParser::new(parse_device), default_value_t = Device::CPU)]
     pub device: Device,
     #[arg(long, value_name = "PATH", help = "The model to use with demucs. Default to htdemucs fine-trained", value_parser = ValueParser::new(parse_model), default_value = DEFAULT_MODEL)]
@@ -104,8 +230,84 @@ pub struct GenerateArgs {
         default_value_t = 4
     )]
     pub thread: usize,
+    #[arg(long, value_name = "INTEGER", help = "The number of files to process at once, sharing the demucs sessions", value_parser = value_parser!(u16).range(1..), default_value_t = 1)]
+    pub jobs: u16,
+    #[arg(long, value_name = "INTEGER", help = "The number of demucs model instances shared by the jobs, each separating one segment at a time with its own copy of the model", value_parser = value_parser!(u16).range(1..), default_value_t = 1)]
+    pub sessions: u16,
+    #[arg(long, value_name = "SIZE", help = "Approximate memory the jobs may take for the segments they separate and encode (e.g. 2G), a job waiting for room before decoding its next segment", value_parser = ValueParser::new(parse_size))]
+    pub memory_limit: Option<usize>,
     #[arg(long, default_value_t = false)]
     pub preserved_original_as_master: bool,
+    #[arg(long, visible_alias = "skip-existing", help = "Only process the inputs which are new or changed since the last run, as recorded in a .stemgen.json file in OUTPUT", default_value_t = false, action = ArgAction::SetTrue)]
+    pub incremental: bool,
+    #[arg(long, value_name = "FORMAT", help = "Write a NI STEM file (stem), one file per stream (wav, flac or aiff) or a single 10 channels file (multichannel-wav or multichannel-aiff)", value_parser = ValueParser::new(parse_output_format), default_value = "stem")]
+    pub format: OutputFormat,
+    #[arg(long, value_name = "BITS", help = "The bit depth of ALAC and FLAC stems or of the audio files (16, 24, 32 for floating point or 32i for integers)", value_parser = ValueParser::new(parse_bit_depth))]
+    pub bit_depth: Option<BitDepth>,
+    #[arg(long, value_name = "FILE", help = "JSON file with the stem labels, colours and mastering DSP to use, as printed by the manifest command", conflicts_with_all = ["drum_stem_label", "bass_stem_label", "other_stem_label", "vocal_stem_label", "drum_stem_color", "bass_stem_color", "other_stem_color", "vocal_stem_color"])]
+    pub manifest: Option<PathBuf>,
+    #[arg(long, help = "Check that the stems reconstruct the master once the NI STEM file is written", default_value_t = false, action = ArgAction::SetTrue)]
+    pub verify: bool,
+    #[arg(long, value_name = "DB", help = "Maximum RMS residual accepted by --verify, in dBFS", allow_negative_numbers = true, default_value_t = DEFAULT_THRESHOLD)]
+    pub verify_threshold: f64,
+    #[command(flatten)]
+    pub artwork: CoverArgs,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExtractArgs {
+    #[arg(value_name = "FILE", help = "path to the STEM file to extract", required = true)]
+    pub input: PathBuf,
+    #[arg(value_name = "OUTPUT", help = "path to an existing directory where to store the extracted files", value_parser = value_parser!(PathBuf), required = true)]
+    pub output: PathBuf,
+    #[arg(long, value_name = "FORMAT", help = "The format of the extracted files (wav, flac or aiff)", value_parser = ValueParser::new(parse_audio_format), default_value = "wav")]
+    pub format: AudioFormat,
+    #[arg(long, value_name = "BITS", help = "The bit depth of the extracted files (16, 24, 32 for floating point or 32i for integers)", value_parser = ValueParser::new(parse_bit_depth), default_value = "16")]
+    pub bit_depth: BitDepth,
+    #[arg(long, help = "Copy the tags and cover art of the STEM file into every extracted file", default_value_t = false, action = ArgAction::SetTrue)]
+    pub copy_tags: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct EditArgs {
+    #[arg(value_name = "FILE", help = "path to the STEM file to edit", required = true)]
+    pub file: PathBuf,
+    #[arg(long, value_name = "KEY=VALUE", help = "Set a mastering DSP parameter, such as 'limiter.enabled=true' or 'compressor.ratio=4'")]
+    pub mastering_dsp: Vec<String>,
+    #[arg(long, value_name = "TEXT", help = "Title of the track, an empty value removes it")]
+    pub title: Option<String>,
+    #[arg(long, value_name = "TEXT", help = "Artist of the track, an empty value removes it")]
+    pub artist: Option<String>,
+    #[arg(long, value_name = "TEXT", help = "Release of the track, an empty value removes it")]
+    pub release: Option<String>,
+    #[arg(long, value_name = "TEXT", help = "Record label of the track, an empty value removes it")]
+    pub record_label: Option<String>,
+    #[arg(long, value_name = "TEXT", help = "Genre of the track, an empty value removes it")]
+    pub genre: Option<String>,
+    #[arg(long, value_name = "INTEGER", help = "Track number, 0 removes it")]
+    pub track_no: Option<u32>,
+    #[arg(long, value_name = "IMAGE", help = "JPEG or PNG image to use as cover art")]
+    pub cover: Option<PathBuf>,
+    #[arg(long, help = "Remove the cover art", conflicts_with = "cover", default_value_t = false, action = ArgAction::SetTrue)]
+    pub remove_cover: bool,
+    #[arg(long, help = "Print the changes without saving them", default_value_t = false, action = ArgAction::SetTrue)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct VerifyArgs {
+    #[arg(value_name = "FILE", help = "path to the STEM file to verify", required = true)]
+    pub file: PathBuf,
+    #[arg(long, value_name = "DB", help = "Maximum RMS residual between the master and the sum of the stems, in dBFS", allow_negative_numbers = true, default_value_t = DEFAULT_THRESHOLD)]
+    pub threshold: f64,
+    #[arg(long, help = "Print the result as JSON", default_value_t = false, action = ArgAction::SetTrue)]
+    pub json: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct ManifestArgs {
+    #[arg(value_name = "FILE", help = "path to the STEM file", required = true)]
+    pub file: PathBuf,
 }
 
 #[derive(Debug, Subcommand)]
@@ -114,6 +316,14 @@ pub enum Commands {
     Generate(GenerateArgs),
     #[command(arg_required_else_help = true)]
     Create(CreateArgs),
+    #[command(arg_required_else_help = true)]
+    Extract(ExtractArgs),
+    #[command(arg_required_else_help = true)]
+    Edit(EditArgs),
+    #[command(arg_required_else_help = true)]
+    Manifest(ManifestArgs),
+    #[command(arg_required_else_help = true)]
+    Verify(VerifyArgs),
 }
 
 impl Default for Commands {
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use clap::ArgMatches;
+use serde::{de, Deserialize, Deserializer};
+use stemgen::{
+    demucs::Model,
+    nistem::{Codec, Color, SampleRate},
+};
+
+use crate::{
+    cli::{Cli, Commands},
+    utils::is_set,
+};
+
+/// Looked up in the current directory and its parents
+pub const PROJECT_CONFIG: &str = "stemgen.toml";
+
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: for<'a> TryFrom<&'a str, Error = String>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| T::try_from(value.as_str()).map_err(de::Error::custom))
+        .transpose()
+}
+
+fn parse_samplerate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SampleRate>, D::Error> {
+    Option::<u32>::deserialize(deserializer)?
+        .map(|value| SampleRate::try_from(value.to_string().as_str()).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Options which can be set in a configuration file, named after their
+/// command line flag
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default, deserialize_with = "parse")]
+    pub codec: Option<Codec>,
+    #[serde(default, deserialize_with = "parse_samplerate")]
+    pub sample_rate: Option<SampleRate>,
+    pub ext: Option<String>,
+    #[serde(default, deserialize_with = "parse")]
+    pub model: Option<Model>,
+    pub compression_level: Option<usize>,
+    pub drum_stem_label: Option<String>,
+    pub bass_stem_label: Option<String>,
+    pub other_stem_label: Option<String>,
+    pub vocal_stem_label: Option<String>,
+    #[serde(default, deserialize_with = "parse")]
+    pub drum_stem_color: Option<Color>,
+    #[serde(default, deserialize_with = "parse")]
+    pub bass_stem_color: Option<Color>,
+    #[serde(default, deserialize_with = "parse")]
+    pub other_stem_color: Option<Color>,
+    #[serde(default, deserialize_with = "parse")]
+    pub vocal_stem_color: Option<Color>,
+}
+
+impl Settings {
+    /// Combine with `other`, whose values take precedence
+    fn merge(self, other: Settings) -> Settings {
+        Settings {
+            codec: other.codec.or(self.codec),
+            sample_rate: other.sample_rate.or(self.sample_rate),
+            ext: other.ext.or(self.ext),
+            model: other.model.or(self.model),
+            compression_level: other.compression_level.or(self.compression_level),
+            drum_stem_label: other.drum_stem_label.or(self.drum_stem_label),
+            bass_stem_label: other.bass_stem_label.or(self.bass_stem_label),
+            other_stem_label: other.other_stem_label.or(self.other_stem_label),
+            vocal_stem_label: other.vocal_stem_label.or(self.vocal_stem_label),
+            drum_stem_color: other.drum_stem_color.or(self.drum_stem_color),
+            bass_stem_color: other.bass_stem_color.or(self.bass_stem_color),
+            other_stem_color: other.other_stem_color.or(self.other_stem_color),
+            vocal_stem_color: other.vocal_stem_color.or(self.vocal_stem_color),
+        }
+    }
+
+    /// Use the settings for the options not given on the command line
+    pub fn apply(self, ctx: &mut Cli, matches: &ArgMatches) {
+        fn set<T>(matches: &ArgMatches, id: &str, field: &mut T, value: Option<T>) {
+            if let (Some(value), false) = (value, is_set(matches, id)) {
+                *field = value;
+            }
+        }
+        set(matches, "codec", &mut ctx.codec, self.codec);
+        set(matches, "sample_rate", &mut ctx.sample_rate, self.sample_rate);
+        set(matches, "ext", &mut ctx.ext, self.ext);
+        set(matches, "compression_level", &mut ctx.compression_level, self.compression_level.map(Some));
+        set(matches, "drum_stem_label", &mut ctx.drum_stem_label, self.drum_stem_label);
+        set(matches, "bass_stem_label", &mut ctx.bass_stem_label, self.bass_stem_label);
+        set(matches, "other_stem_label", &mut ctx.other_stem_label, self.other_stem_label);
+        set(matches, "vocal_stem_label", &mut ctx.vocal_stem_label, self.vocal_stem_label);
+        set(matches, "drum_stem_color", &mut ctx.drum_stem_color, self.drum_stem_color);
+        set(matches, "bass_stem_color", &mut ctx.bass_stem_color, self.bass_stem_color);
+        set(matches, "other_stem_color", &mut ctx.other_stem_color, self.other_stem_color);
+        set(matches, "vocal_stem_color", &mut ctx.vocal_stem_color, self.vocal_stem_color);
+        if let Commands::Generate(command) = &mut ctx.command {
+            set(matches, "model", &mut command.model, self.model);
+        }
+    }
+}
+
+/// A configuration file: default settings, and named presets to apply on top
+/// of them with `--preset`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: Settings,
+    #[serde(default)]
+    pub presets: HashMap<String, Settings>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("unable to read {}: {e}", path.display()))?;
+        toml::from_str(&content).map_err(|e| format!("invalid configuration in {}: {e}", path.display()))
+    }
+
+    /// Load the user configuration, then the project one, which takes
+    /// precedence
+    pub fn load() -> Result<Self, String> {
+        paths()
+            .iter()
+            .try_fold(Config::default(), |config, path| Ok(config.merge(Config::from_file(path)?)))
+    }
+
+    /// Combine with `other`, whose defaults and presets take precedence
+    fn merge(mut self, other: Config) -> Config {
+        self.defaults = self.defaults.merge(other.defaults);
+        for (name, preset) in other.presets {
+            let merged = self.presets.remove(&name).unwrap_or_default().merge(preset);
+            self.presets.insert(name, merged);
+        }
+        self
+    }
+
+    /// The defaults, with `preset` applied on top if given
+    pub fn settings(&self, preset: Option<&str>) -> Result<Settings, String> {
+        let Some(name) = preset else {
+            return Ok(self.defaults.clone());
+        };
+        let preset = self.presets.get(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.presets.keys().map(String::as_str).collect();
+            names.sort();
+            match names.is_empty() {
+                true => format!("unknown preset {name}, no preset is configured"),
+                false => format!("unknown preset {name}, expected one of {}", names.join(", ")),
+            }
+        })?;
+        Ok(self.defaults.clone().merge(preset.clone()))
+    }
+}
+
+/// The existing configuration files: `$XDG_CONFIG_HOME/stemgen/config.toml`
+/// (`~/.config` by default), then the closest `stemgen.toml` from the
+/// current directory up.
+pub fn paths() -> Vec<PathBuf> {
+    let user = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("stemgen").join("config.toml"))
+        .filter(|path| path.is_file());
+    let project = std::env::current_dir().ok().and_then(|dir| {
+        dir.ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG))
+            .find(|path| path.is_file())
+    });
+    user.into_iter().chain(project).collect()
+}
+
+/// Fill the options of `generate` and `create` not given on the command line
+/// from the configuration files and the selected preset.
+pub fn apply(ctx: &mut Cli, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    if !matches!(ctx.command, Commands::Generate(_) | Commands::Create(_)) {
+        return Ok(());
+    }
+    let settings = Config::load()?.settings(ctx.preset.as_deref())?;
+    settings.apply(ctx, matches);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+    use stemgen::nistem::{Codec, Color, SampleRate};
+
+    use crate::{config::Config, Cli};
+
+    const CONFIG: &str = r##"
+[defaults]
+codec = "flac"
+drum-stem-label = "Beat"
+
+[presets.techno]
+drum-stem-label = "Kick"
+drum-stem-color = "#FF0000"
+
+[presets.archive]
+codec = "alac"
+sample-rate = 48000
+"##;
+
+    #[test]
+    fn test_config_precedence() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let project: Config = toml::from_str("[presets.archive]\nsample-rate = 96000").unwrap();
+        let config = config.merge(project);
+
+        let settings = config.settings(None).unwrap();
+        assert!(matches!(settings.codec, Some(Codec::FLAC)));
+        assert_eq!(settings.drum_stem_label.as_deref(), Some("Beat"));
+
+        let settings = config.settings(Some("archive")).unwrap();
+        assert!(matches!(settings.codec, Some(Codec::ALAC)));
+        assert_eq!(settings.sample_rate, Some(SampleRate::Hz96000));
+        assert_eq!(settings.drum_stem_label.as_deref(), Some("Beat"));
+        assert!(config.settings(Some("house")).is_err());
+
+        // Flags given on the command line win over the preset
+        let matches = Cli::command()
+            .try_get_matches_from(["stemgen", "create", "--mastered", "m.mp3", "--drum", "d.mp3", "--bass", "b.mp3", "--other", "o.mp3", "--vocal", "v.mp3", "--codec", "aac", "out.stem.mp4"])
+            .unwrap();
+        let mut ctx = Cli::from_arg_matches(&matches).unwrap();
+        config.settings(Some("techno")).unwrap().apply(&mut ctx, &matches);
+        assert!(matches!(ctx.codec, Codec::AAC));
+        assert_eq!(ctx.drum_stem_label, "Kick");
+        assert_eq!(ctx.drum_stem_color, Color(0xFF0000));
+        assert_eq!(ctx.bass_stem_label, "Bass");
+
+        assert!(toml::from_str::<Config>("[defaults]\ncodec = \"mp3\"").is_err());
+        assert!(toml::from_str::<Config>("[defaults]\nunknown = 1").is_err());
+    }
+}
@@ -1,87 +1,96 @@
 
 use indicatif::{ProgressBar, ProgressStyle};
-use stemgen::{nistem::{self, NIStem}, track::Track};
+use stemgen::{multitrack::OutputFormat, track::Track, verify};
 
-use crate::cli::{Cli, CreateArgs};
+use crate::{
+    cli::{Cli, CreateArgs},
+    output::{check_format, output_paths, preserved_bit_depth, sample_rate, Output},
+    utils::{enable_loudness, normalize, print_loudness, print_verification, split_file_at_dot},
+};
 
 pub fn create(ctx: &Cli, command: &CreateArgs) -> Result<bool, Box<dyn std::error::Error>> {
-        let output_file = &command.output;
-        if output_file.exists() {
-            if !ctx.force {
-                eprintln!("Cannot proceed with {}: stem file already exist in output directory!", output_file.display());
-                return Ok(true);
+        check_format(ctx, command.format, false)?;
+        if command.verify && command.format != OutputFormat::Stem {
+            return Err("only NI STEM files can be verified".into());
+        }
+        let output_files = match command.format {
+            OutputFormat::Stem => vec![command.output.clone()],
+            format => {
+                let filename = command
+                    .output
+                    .file_name()
+                    .map(split_file_at_dot)
+                    .map(|(before, _after)| before.to_string_lossy().into_owned())
+                    .ok_or("unable to detect filename")?;
+                let dir = command.output.parent().unwrap_or(".".as_ref());
+                output_paths(ctx, format, dir, &filename)?
+            }
+        };
+        for output_file in &output_files {
+            if output_file.exists() {
+                if !ctx.force {
+                    eprintln!("Cannot proceed with {}: stem file already exist in output directory!", output_file.display());
+                    return Ok(true);
+                }
             }
-            std::fs::remove_file(output_file)?;
         }
+        let output_file = &command.output;
+        // Read the inputs at the output rate, so they're only resampled once
+        let rate = sample_rate(ctx, command.format)?;
         let mut inputs = [
-            Track::new(&command.mastered)?,
-            Track::new(&command.drum)?,
-            Track::new(&command.bass)?,
-            Track::new(&command.other)?,
-            Track::new(&command.vocal)?,
+            Track::new_with_sample_rate(&command.mastered, rate)?,
+            Track::new_with_sample_rate(&command.drum, rate)?,
+            Track::new_with_sample_rate(&command.bass, rate)?,
+            Track::new_with_sample_rate(&command.other, rate)?,
+            Track::new_with_sample_rate(&command.vocal, rate)?,
         ];
-        let mut nistem = NIStem::new_with_consistent_streams(output_file,ctx)?;
+        let depth = command
+            .bit_depth
+            .or_else(|| preserved_bit_depth(ctx, command.format, &inputs));
+        let mut output = Output::new(ctx, command.format, &output_files, None, depth)?;
         if command.copy_id3tags_from_mastered {
-            nistem.clone(&command.mastered)?;
+            output.clone(&command.mastered)?;
+        }
+        output.apply_cover(&command.artwork, &command.mastered)?;
+        if let (Some(target), Output::Stem(nistem)) = (ctx.normalize, &mut output) {
+            if let Some(warning) = normalize(nistem, &command.mastered, target)? {
+                eprintln!("{warning}");
+            }
+        }
+        if let Output::Stem(nistem) = &mut output {
+            enable_loudness(ctx, nistem);
         }
         let mut read = 0;
-        let sample_rate: u64 = ctx.sample_rate.into();
-        let pb = ProgressBar::new(2 * inputs[0].total() as u64);
+        let pb = ProgressBar::new(inputs[0].total() as u64);
             pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent}% ({eta})")
                 .unwrap()
                 .progress_chars("#>-"));
 
         loop {
-            let mut data = vec![
-                vec![0f32; 204800],
-                vec![0f32; 204800],
-                vec![0f32; 204800],
-                vec![0f32; 204800],
-                vec![0f32; 204800],
-            ];
+            let data = inputs
+                .iter_mut()
+                .map(|input| input.read(None, 102400))
+                .collect::<Result<Vec<_>, _>>()?;
 
-            let eof = {
-                let size = 0;
-                for (i, buf) in data.iter_mut().enumerate() {
-                    let size = inputs[i].read(None, buf)?;
-                    if i == 0 {
-                        read += size;
-                    }
-                }
-                size != data[0].len()
-            };
-            pb.set_position(read as u64 / sample_rate);
-            nistem.write_consistent(data)?;
+            read += data[0].frames();
+            let eof = data[0].frames() != 102400;
+            pb.set_position(read as u64 / data[0].sample_rate() as u64);
+            output.write(vec![], data)?;
             if eof {
                 break;
             }
         }
 
         pb.finish_with_message(format!("Processed {}", output_file.display()));
-        nistem.flush(
-            nistem::Atom {
-                stems: [
-                    nistem::AtomStem{
-                        color: ctx.drum_stem_color.to_owned(),
-                        name: ctx.drum_stem_label.to_owned(),
-                    },
-                    nistem::AtomStem{
-                        color: ctx.bass_stem_color.to_owned(),
-                        name: ctx.bass_stem_label.to_owned(),
-                    },
-                    nistem::AtomStem{
-                        color: ctx.other_stem_color.to_owned(),
-                        name: ctx.other_stem_label.to_owned(),
-                    },
-                    nistem::AtomStem{
-                        color: ctx.vocal_stem_color.to_owned(),
-                        name: ctx.vocal_stem_label.to_owned(),
-                    },
-                ],
-                version: 1,
-                ..Default::default()
-            }
-        )?;
+        if let Some(loudness) = output.loudness() {
+            print_loudness(ctx, output_file, &loudness);
+        }
+        output.flush(ctx)?;
+        if command.verify {
+            let verification = verify::verify(output_file, command.verify_threshold)?;
+            print_verification(&verification);
+            return Ok(!verification.passed);
+        }
     Ok(false)
 }
 
@@ -89,7 +98,7 @@ pub fn create(ctx: &Cli, command: &CreateArgs) -> Result<bool, Box<dyn std::erro
 #[cfg(test)]
 mod tests {
 
-    use stemgen::nistem::{Codec, SampleRate};
+    use stemgen::{multitrack::OutputFormat, nistem::{Codec, SampleRate}, verify::DEFAULT_THRESHOLD};
 
     use crate::{cli::CreateArgs, create::create, Cli, Commands};
 
@@ -108,6 +117,12 @@ mod tests {
                 other:"../testdata/Oddchap - Sound 104.mp3".into(),
                 vocal:"../testdata/Oddchap - Sound 104.mp3".into(),
                 copy_id3tags_from_mastered: true,
+                format: OutputFormat::Stem,
+                bit_depth: None,
+                manifest: None,
+                verify: false,
+                verify_threshold: DEFAULT_THRESHOLD,
+                artwork: Default::default(),
             }),
             ..Default::default()
         };
@@ -0,0 +1,73 @@
+use clap::ArgMatches;
+use stemgen::{constant::{Metadata, MetadataValue}, cover, editor::NIStemEditor};
+
+use crate::{cli::{Cli, EditArgs}, utils::is_set};
+
+pub fn edit(ctx: &Cli, command: &EditArgs, matches: &ArgMatches) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut editor = NIStemEditor::open(&command.file)?;
+
+    let stems = [
+        ("drum_stem_label", &ctx.drum_stem_label, "drum_stem_color", &ctx.drum_stem_color),
+        ("bass_stem_label", &ctx.bass_stem_label, "bass_stem_color", &ctx.bass_stem_color),
+        ("other_stem_label", &ctx.other_stem_label, "other_stem_color", &ctx.other_stem_color),
+        ("vocal_stem_label", &ctx.vocal_stem_label, "vocal_stem_color", &ctx.vocal_stem_color),
+    ];
+    for (idx, (label_id, label, color_id, color)) in stems.into_iter().enumerate() {
+        if is_set(matches, label_id) {
+            editor.manifest_mut().stems[idx].name = label.to_owned();
+        }
+        if is_set(matches, color_id) {
+            editor.manifest_mut().stems[idx].color = color.to_owned();
+        }
+    }
+    for setting in &command.mastering_dsp {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or(format!("expected KEY=VALUE for --mastering-dsp, got {setting}"))?;
+        editor.manifest_mut().set_mastering_dsp(key, value)?;
+    }
+
+    let tags = [
+        (Metadata::Title, &command.title),
+        (Metadata::Artist, &command.artist),
+        (Metadata::Release, &command.release),
+        (Metadata::Label, &command.record_label),
+        (Metadata::Genre, &command.genre),
+    ];
+    for (key, value) in tags {
+        match value.as_deref() {
+            Some("") => editor.remove_metadata(&key),
+            Some(value) => editor.set_metadata(key, MetadataValue::String(value.to_owned())),
+            None => {}
+        }
+    }
+    match command.track_no {
+        Some(0) => editor.remove_metadata(&Metadata::TrackNo),
+        Some(value) => editor.set_metadata(Metadata::TrackNo, MetadataValue::Number(value)),
+        None => {}
+    }
+
+    if let Some(path) = &command.cover {
+        editor.set_cover(vec![cover::from_file(path)?]);
+    } else if command.remove_cover {
+        editor.set_cover(vec![]);
+    }
+
+    let changes = editor.changes();
+    if changes.is_empty() {
+        println!("Nothing to change in {}", command.file.display());
+        return Ok(false);
+    }
+    if command.dry_run {
+        println!("Would change {}:", command.file.display());
+    } else {
+        println!("Changing {}:", command.file.display());
+    }
+    for change in &changes {
+        println!("  {change}");
+    }
+    if !command.dry_run {
+        editor.save()?;
+    }
+    Ok(false)
+}
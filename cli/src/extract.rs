@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use stemgen::{audiofile::AudioFileWriter, reader::NIStemReader};
+
+use crate::{cli::{Cli, ExtractArgs}, utils::{sanitize_filename, split_file_at_dot}};
+
+pub fn extract(ctx: &Cli, command: &ExtractArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut reader = NIStemReader::open(&command.input)?;
+    let manifest = reader.manifest()?.unwrap_or_default();
+    let filename = command
+        .input
+        .file_name()
+        .map(split_file_at_dot)
+        .map(|(before, _after)| before.to_string_lossy().into_owned())
+        .ok_or("unable to detect filename")?;
+
+    let labels = std::iter::once("Master".to_owned())
+        .chain(manifest.stems.iter().map(|stem| sanitize_filename(&stem.name)));
+    let mut outputs: Vec<PathBuf> = Vec::new();
+    for label in labels {
+        let output = command.output.join(format!(
+            "{filename} - {label}.{}",
+            command.format.extension()
+        ));
+        if outputs.contains(&output) {
+            return Err(format!("more than one stream would be extracted to {}", output.display()).into());
+        }
+        if output.exists() {
+            if !ctx.force {
+                eprintln!("Cannot proceed with {}: file already exist in output directory!", output.display());
+                return Ok(true);
+            }
+        }
+        outputs.push(output);
+    }
+
+    let pb = ProgressBar::new(reader.total() as u64);
+    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent}% ({eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+
+    let mut writers: Vec<AudioFileWriter> = Vec::new();
+    let mut read = 0;
+    loop {
+        let data = reader.read(102400)?;
+        if writers.is_empty() {
+            for (output, buffer) in outputs.iter().zip(&data) {
+                let mut writer = AudioFileWriter::new(
+                    output,
+                    command.format,
+                    command.bit_depth,
+                    buffer.channels(),
+                    buffer.sample_rate(),
+                )?;
+                if command.copy_tags {
+                    writer.set_tags(reader.tags(), reader.covers());
+                }
+                writers.push(writer);
+            }
+        }
+
+        read += data[0].frames();
+        let eof = data.iter().all(|buffer| buffer.frames() != 102400);
+        pb.set_position(read as u64 / data[0].sample_rate() as u64);
+        for (writer, buffer) in writers.iter_mut().zip(&data) {
+            writer.write(buffer)?;
+        }
+        if eof {
+            break;
+        }
+    }
+
+    for writer in writers {
+        writer.flush()?;
+    }
+    pb.finish_with_message(format!("Extracted {}", command.input.display()));
+    Ok(false)
+}
@@ -1,182 +1,224 @@
-use std::{ffi::OsStr, path::PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
-use glob::glob;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use stemgen::{
-    demucs::{Demucs, DemusOpts},
-    nistem::{self, NIStem},
+    buffer::{AudioBuffer, Layout},
+    demucs::{self, Demucs, DemusOpts},
+    multitrack::OutputFormat,
     track::Track,
+    verify,
 };
 
-use crate::cli::{Cli, GenerateArgs};
-
-fn split_file_at_dot(file: &OsStr) -> (&OsStr, Option<&OsStr>) {
-    let slice = file.as_encoded_bytes();
-    if slice == b".." {
-        return (file, None);
-    }
-
-    let mut current_idx = slice.len();
+use crate::{
+    cli::{Cli, GenerateArgs},
+    incremental::{Batch, Settings, Snapshot, Status},
+    inputs::{self, Input},
+    output::{check_format, output_paths, preserve_original, Output},
+    scheduler::{Budget, SEGMENT_MEMORY},
+    utils::{enable_loudness, normalize, print_loudness, print_verification},
+};
 
-    while current_idx > 0 {
-        match slice[1..current_idx].iter().rposition(|b| *b == b'.') {
-            Some(i) =>  {
-                let ext = &slice[i+1..].to_ascii_lowercase();
-                if !ext.iter().all(|c|(*c >= b'0' && *c <= b'9') || (*c >= b'a' && *c <= b'z') || *c == b'.') {
-                    break;
-                }
-                current_idx = i + 1;
-            },
-            None => break,
-        };
+pub fn generate(ctx: &Cli, command: &GenerateArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    if command.preserved_original_as_master && ctx.normalize.is_some() {
+        return Err("loudness normalization cannot be used when preserving the original as master".into());
     }
-
-    if current_idx == slice.len() {
-        return (file, None)
+    check_format(ctx, command.format, command.preserved_original_as_master)?;
+    if command.verify && command.format != OutputFormat::Stem {
+        return Err("only NI STEM files can be verified".into());
     }
+    let inputs = match &command.recursive {
+        Some(root) => inputs::from_tree(root, &command.output, &ctx.ext, command.name_template.as_ref())?,
+        None => inputs::from_globs(&command.files, &command.output, command.name_template.as_ref())?,
+    };
 
-    let before = &slice[..current_idx];
-    let after = &slice[current_idx..];
-    unsafe {
-        (
-            OsStr::from_encoded_bytes_unchecked(before),
-            Some(OsStr::from_encoded_bytes_unchecked(after)),
-        )
+    let batch = match command.incremental {
+        true => Some(Batch::open(&command.output, Settings::new(ctx, command)?)?),
+        false => None,
+    };
+    for (input, outputs) in batch.iter().flat_map(Batch::orphans) {
+        let outputs: Vec<String> = outputs.iter().map(|output| output.display().to_string()).collect();
+        eprintln!("{} no longer exists, its outputs are orphaned: {}", input.display(), outputs.join(", "));
     }
-}
 
-pub fn generate(ctx: &Cli, command: &GenerateArgs) -> Result<bool, Box<dyn std::error::Error>> {
-    let mut demucs = Demucs::new_from_file(
+    let demucs = Demucs::new_from_file(
         &command.model,
         DemusOpts {
             threads: command.thread,
             device: command.device,
+            sessions: command.sessions as usize,
         },
     )?;
-    let mut has_failure = false;
-    let sample_rate: u64 = ctx.sample_rate.into();
+    let budget = command.memory_limit.map(Budget::new);
 
-    let mut files: Vec<Result<glob::Paths, glob::PatternError>> = command.files.iter().map(|raw|glob(&raw)).collect();
+    // Each job takes the next file until none is left, a failure only
+    // affecting its file
+    let progress = MultiProgress::new();
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..(command.jobs as usize).clamp(1, inputs.len().max(1)) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(idx) else {
+                    break;
+                };
+                let has_failure = process(ctx, command, input, demucs.share(), budget.as_ref(), batch.as_ref(), &progress)
+                    .unwrap_or_else(|err| {
+                        progress.suspend(|| eprintln!("Unable to process {}: {err}", input.file.display()));
+                        true
+                    });
+                if has_failure {
+                    failed.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    Ok(failed.into_inner())
+}
 
-    if let Some(err) = files.iter().find_map(|r|r.as_ref().err()) {
-        return Err(format!("unable to render the glob: {}", err).into())
+/// The first of `outputs` already existing which can't be replaced: only
+/// the ones `file` was last processed into are replaced without `--force`
+fn existing_output<'a>(
+    ctx: &Cli,
+    file: &Path,
+    outputs: &'a [PathBuf],
+    batch: Option<&Batch>,
+) -> Result<Option<&'a PathBuf>, Box<dyn std::error::Error>> {
+    if ctx.force {
+        return Ok(None);
     }
+    for output in outputs.iter().filter(|output| output.exists()) {
+        if !batch.map(|batch| batch.recorded(file, output)).transpose()?.unwrap_or(false) {
+            return Ok(Some(output));
+        }
+    }
+    Ok(None)
+}
 
-    let files: Vec<PathBuf> = files.iter_mut().filter_map(|r|r.as_mut().ok()).flatten().filter_map(|r|r.ok()).collect();
-
-    for file in &files {
-        let filename = file.file_name().map(split_file_at_dot).and_then(|(before, _after)| Some(before));
-        if filename.is_none() {
+/// Generate the outputs of one input, returning whether it failed
+fn process(
+    ctx: &Cli,
+    command: &GenerateArgs,
+    Input { file, dir, name: filename }: &Input,
+    mut demucs: Demucs,
+    budget: Option<&Budget>,
+    batch: Option<&Batch>,
+    progress: &MultiProgress,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let output_files = output_paths(ctx, command.format, dir, filename)?;
+    if let Some(batch) = batch {
+        if batch.status(file, &output_files)? == Status::UpToDate {
+            progress.suspend(|| println!("{}: up to date, skipping", file.display()));
+            return Ok(false);
+        }
+    }
+    if let Some(output_file) = existing_output(ctx, file, &output_files, batch)? {
+        progress.suspend(|| {
             eprintln!(
-                "Unable to detect filename from {}",
-                file.display()
-            );
-            has_failure |= true;
-            continue;
+                "Cannot proceed with {}: stem file already exist in output directory!",
+                output_file.display()
+            )
+        });
+        return Ok(true);
+    }
+    // The input may change while processed
+    let snapshot = batch.map(|_| Snapshot::new(file)).transpose()?;
+    let output_file = &output_files[0];
+    std::fs::create_dir_all(dir).map_err(|e| format!("unable to create {}: {e}", dir.display()))?;
+    let mut input = Track::new(file)?;
+    // Only probed once the input is known to be processed
+    let preserved = command.preserved_original_as_master && preserve_original(ctx, file, &input, progress);
+    let mut output = Output::new(
+        ctx,
+        command.format,
+        &output_files,
+        preserved.then(|| input.args()),
+        command.bit_depth,
+    )?;
+    output.clone(file)?;
+    output.apply_cover(&command.artwork, file)?;
+    if let (Some(target), Output::Stem(nistem)) = (ctx.normalize, &mut output) {
+        if let Some(warning) = normalize(nistem, file, target)? {
+            progress.suspend(|| eprintln!("{warning}"));
         }
-        let filename = filename.unwrap();
-        let output_filename = format!(
-            "{}.{}",
-            filename.display(),
-            ctx.ext
-        );
-        let output_file = command.output.join(output_filename);
-        if output_file.exists() {
-            if !ctx.force {
-                eprintln!(
-                    "Cannot proceed with {}: stem file already exist in output directory!",
-                    output_file.display()
-                );
-                has_failure |= true;
-                continue;
+    }
+    if let Output::Stem(nistem) = &mut output {
+        enable_loudness(ctx, nistem);
+        // The jobs already keep every core busy
+        nistem.set_parallel_encoding(command.jobs <= 1);
+    }
+    let mut read = 0;
+    let pb = progress.add(ProgressBar::new(input.total() as u64));
+    pb.set_style(
+        ProgressStyle::with_template(
+            &format!("{{spinner:.green}} {filename} [{{wide_bar:.cyan/blue}}] [{{elapsed_precise}}] {{percent}}% ({{eta}})"),
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+
+    loop {
+        // Held until the segment is written
+        let _permit = budget.map(|budget| budget.acquire(SEGMENT_MEMORY));
+        let mut original_packets = Vec::with_capacity(512);
+        let mut original_buffer = AudioBuffer::new(2, demucs::SAMPLE_RATE, 0, Layout::Interleaved);
+
+        let (data, eof) = loop {
+            let buf = input.read(
+                if output.is_preserved() {
+                    Some(&mut original_packets)
+                } else {
+                    None
+                },
+                demucs::SEGMENT_LENGTH,
+            )?;
+            read += buf.frames();
+            if output.is_preserved() {
+                output.analyze_master(&buf);
+            } else {
+                original_buffer.append(&buf)?;
             }
-            std::fs::remove_file(&output_file)?;
-        }
-        let mut input = Track::new(file)?;
-        let mut nistem = if command.preserved_original_as_master {
-            NIStem::new_with_preserved_original(&output_file, input.args(), ctx)?
-        } else {
-            NIStem::new_with_consistent_streams(&output_file, ctx)?
-        };
-        nistem.clone(file)?;
-        let mut read = 0;
-        let pb = ProgressBar::new(2 * input.total() as u64);
-        pb.set_style(
-            ProgressStyle::with_template(
-                &format!("{{spinner:.green}} {} [{{wide_bar:.cyan/blue}}] [{{elapsed_precise}}] {{percent}}% ({{eta}})", filename.display()),
-            )
-            .unwrap()
-            .progress_chars("#>-"),
-        );
-
-        loop {
-            let mut buf: Vec<f32> = vec![0f32; 343980 * 2];
-            let mut original_packets = Vec::with_capacity(512);
-            let mut original_buffer: Vec<f32> = Vec::with_capacity(512);
-
-            let (data, eof) = loop {
-                let size = input.read(
-                    if matches!(nistem, NIStem::PreservedMaster(..)) {
-                        Some(&mut original_packets)
-                    } else {
-                        None
-                    },
-                    &mut buf,
-                )?;
-                read += size;
-                if matches!(nistem, NIStem::ConsistentStream(..)) {
-                    original_buffer.extend(buf[..size].to_vec());
+            if let Some(mut data) = demucs.send(&buf)? {
+                if !output.is_preserved() {
+                    data.insert(0, original_buffer);
                 }
-                if let Some(mut data) = demucs.send(&buf[..size])? {
-                    if matches!(nistem, NIStem::ConsistentStream(..)) {
-                        data.insert(0, original_buffer);
-                    }
-                    break (data, false)
-                }
-                if size != buf.len() {
-                    let mut data = demucs.flush()?;
-                    if matches!(nistem, NIStem::ConsistentStream(..)) {
-                        data.insert(0, original_buffer);
-                    }
-                    break (data, true);
+                break (data, false)
+            }
+            if buf.frames() != demucs::SEGMENT_LENGTH {
+                let mut data = demucs.flush()?;
+                if !output.is_preserved() {
+                    data.insert(0, original_buffer);
                 }
-            };
-            pb.set_position(read as u64 / sample_rate);
-            match nistem {
-                NIStem::PreservedMaster(..) => nistem.write_preserved(original_packets, data)?,
-                NIStem::ConsistentStream(..) => nistem.write_consistent(data)?,
+                break (data, true);
             }
+        };
+        pb.set_position(read as u64 / demucs::SAMPLE_RATE as u64);
+        output.write(original_packets, data)?;
 
-            if eof {
-                break;
-            }
+        if eof {
+            break;
         }
+    }
 
-        pb.finish_with_message(format!("downloaded {}", filename.display()));
-        nistem.flush(nistem::Atom {
-            stems: [
-                nistem::AtomStem {
-                    color: ctx.drum_stem_color.to_owned(),
-                    name: ctx.drum_stem_label.to_owned(),
-                },
-                nistem::AtomStem {
-                    color: ctx.bass_stem_color.to_owned(),
-                    name: ctx.bass_stem_label.to_owned(),
-                },
-                nistem::AtomStem {
-                    color: ctx.other_stem_color.to_owned(),
-                    name: ctx.other_stem_label.to_owned(),
-                },
-                nistem::AtomStem {
-                    color: ctx.vocal_stem_color.to_owned(),
-                    name: ctx.vocal_stem_label.to_owned(),
-                },
-            ],
-            version: 1,
-            ..Default::default()
-        })?;
+    pb.finish_with_message(format!("downloaded {filename}"));
+    if let Some(loudness) = output.loudness() {
+        progress.suspend(|| print_loudness(ctx, output_file, &loudness));
     }
-    Ok(has_failure)
+    output.flush(ctx)?;
+    if command.verify {
+        let verification = verify::verify(output_file, command.verify_threshold)?;
+        progress.suspend(|| print_verification(&verification));
+        if !verification.passed {
+            return Ok(true);
+        }
+    }
+    if let (Some(batch), Some(snapshot)) = (batch, snapshot) {
+        batch.record(file, snapshot, &output_files)?;
+    }
+    Ok(false)
 }
 
 #[cfg(test)]
@@ -184,9 +226,17 @@ mod tests {
 
     use std::path::Path;
 
+    use clap::Parser;
     use stemgen::nistem::{Codec, SampleRate};
 
-    use crate::{cli::GenerateArgs, constants::DEFAULT_EXT, generate::{generate, split_file_at_dot}, Cli, Commands};
+    use crate::{
+        cli::GenerateArgs,
+        constants::DEFAULT_EXT,
+        generate::{existing_output, generate},
+        incremental::{Batch, Settings, Snapshot},
+        utils::split_file_at_dot,
+        Cli, Commands,
+    };
 
     #[test]
     fn test_generate_command() {
@@ -239,6 +289,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_existing_output() {
+        let dir = std::env::temp_dir().join("test_existing_output");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.mp3");
+        let recorded = vec![dir.join("input.stem.mp4")];
+        let renamed = vec![dir.join("renamed.stem.mp4")];
+        std::fs::write(&input, b"audio").unwrap();
+        std::fs::write(&recorded[0], b"stem").unwrap();
+        std::fs::write(&renamed[0], b"someone else's stem").unwrap();
+
+        let ctx = Cli::try_parse_from(["stemgen", "generate", "input.mp3", "out"]).unwrap();
+        let Commands::Generate(command) = &ctx.command else {
+            unreachable!("unexpected command value")
+        };
+        let batch = Batch::open(&dir, Settings::new(&ctx, command).unwrap()).unwrap();
+        assert_eq!(existing_output(&ctx, &input, &recorded, None).unwrap(), Some(&recorded[0]));
+        batch.record(&input, Snapshot::new(&input).unwrap(), &recorded).unwrap();
+
+        // Only what the batch wrote is replaced, even once the outputs
+        // changed path
+        assert_eq!(existing_output(&ctx, &input, &recorded, Some(&batch)).unwrap(), None);
+        assert_eq!(existing_output(&ctx, &input, &renamed, Some(&batch)).unwrap(), Some(&renamed[0]));
+
+        let ctx = Cli::try_parse_from(["stemgen", "--force", "generate", "input.mp3", "out"]).unwrap();
+        assert_eq!(existing_output(&ctx, &input, &renamed, Some(&batch)).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_can_get_file_name(){
         let file_name = Path::new("Flo Rida - Low (feat. T-Pain).ogg").file_name().map(split_file_at_dot).and_then(|(before, _after)| Some(before)).unwrap().to_str().unwrap().to_owned();
@@ -0,0 +1,333 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use stemgen::{dsp::MasteringDSP, multitrack::OutputFormat, nistem::Atom, partial::PartialFile};
+
+use crate::{
+    cli::{Cli, GenerateArgs},
+    output::manifest,
+};
+
+/// Written in the output directory
+pub const MANIFEST_FILE: &str = ".stemgen.json";
+
+/// Everything affecting the outputs besides the input itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub model: String,
+    pub codec: String,
+    pub sample_rate: u32,
+    pub format: String,
+    pub bit_depth: Option<String>,
+    pub bitrate: Option<usize>,
+    pub quality: Option<f32>,
+    pub vbr: Option<bool>,
+    pub compression_level: Option<usize>,
+    pub opus_application: Option<String>,
+    pub preserved_original_as_master: bool,
+    pub normalize: Option<f64>,
+    pub loudness: bool,
+    pub replaygain: bool,
+    pub stems: Atom,
+    /// Whether the master is rendered through the mastering DSP
+    pub render_master: bool,
+    pub name_template: Option<String>,
+    pub cover: Option<PathBuf>,
+    /// SHA-256 of the cover image, which may change under the same path
+    pub cover_hash: Option<String>,
+    pub cover_max_size: Option<u32>,
+    pub cover_format: Option<String>,
+    pub cover_type: String,
+}
+
+impl Settings {
+    pub fn new(ctx: &Cli, command: &GenerateArgs) -> Result<Self, Box<dyn std::error::Error>> {
+        let stems = manifest(ctx)?;
+        let artwork = &command.artwork;
+        Ok(Self {
+            model: command.model.to_string(),
+            codec: ctx.codec.to_string(),
+            sample_rate: ctx.sample_rate.into(),
+            format: command.format.to_string(),
+            bit_depth: command.bit_depth.map(|depth| depth.to_string()),
+            bitrate: ctx.bitrate,
+            quality: ctx.quality,
+            vbr: ctx.vbr,
+            compression_level: ctx.compression_level,
+            opus_application: ctx.opus_application.map(|application| application.to_string()),
+            preserved_original_as_master: command.preserved_original_as_master,
+            normalize: ctx.normalize,
+            loudness: ctx.loudness,
+            replaygain: ctx.replaygain,
+            render_master: command.format == OutputFormat::Stem
+                && !command.preserved_original_as_master
+                && MasteringDSP::is_enabled(&stems.mastering_dsp),
+            stems,
+            name_template: command.name_template.as_ref().map(|template| template.to_string()),
+            cover: artwork.cover.as_ref().map(std::path::absolute).transpose()?,
+            cover_hash: artwork.cover.as_deref().map(hash).transpose()?,
+            cover_max_size: artwork.cover_max_size,
+            cover_format: artwork.cover_format.map(|format| format.to_string()),
+            cover_type: artwork.cover_type.to_string(),
+        })
+    }
+}
+
+/// How an input was last processed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub size: u64,
+    /// Modification time, in nanoseconds since the Unix epoch
+    pub modified: u64,
+    /// SHA-256 of the content
+    pub hash: String,
+    pub settings: Settings,
+    /// Relative to the output directory
+    pub outputs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// By absolute input path
+    records: BTreeMap<PathBuf, Record>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    New,
+    /// The input, the settings or the outputs changed since processed
+    Changed,
+    UpToDate,
+}
+
+/// The size, modification time and content of an input, taken before it is
+/// processed so that changes made meanwhile are noticed on the next run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    size: u64,
+    modified: u64,
+    hash: String,
+}
+
+impl Snapshot {
+    pub fn new(file: &Path) -> std::io::Result<Self> {
+        let (size, modified) = stat(file)?;
+        Ok(Self {
+            size,
+            modified,
+            hash: hash(file)?,
+        })
+    }
+}
+
+fn stat(file: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(file)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|modified| modified.as_nanos() as u64)
+        .unwrap_or_default();
+    Ok((metadata.len(), modified))
+}
+
+fn hash(file: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(file)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The inputs processed into an output directory, kept in its
+/// `.stemgen.json` so that a batch only processes new or changed inputs when
+/// run again.
+#[derive(Debug)]
+pub struct Batch {
+    dir: PathBuf,
+    settings: Settings,
+    manifest: Mutex<Manifest>,
+}
+
+impl Batch {
+    pub fn open(dir: &Path, settings: Settings) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dir.join(MANIFEST_FILE);
+        let manifest = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("invalid batch manifest {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest {
+                version: 1,
+                ..Default::default()
+            },
+            Err(e) => return Err(format!("unable to read {}: {e}", path.display()).into()),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            settings,
+            manifest: Mutex::new(manifest),
+        })
+    }
+
+    fn relative(&self, outputs: &[PathBuf]) -> Vec<PathBuf> {
+        outputs
+            .iter()
+            .map(|output| output.strip_prefix(&self.dir).unwrap_or(output).to_path_buf())
+            .collect()
+    }
+
+    /// Whether `file` needs to be processed into `outputs`. The content is
+    /// only hashed when its size or modification time changed.
+    pub fn status(&self, file: &Path, outputs: &[PathBuf]) -> Result<Status, Box<dyn std::error::Error>> {
+        let key = std::path::absolute(file)?;
+        let record = match self.manifest.lock().map_err(|_| "the batch manifest is poisoned")?.records.get(&key) {
+            Some(record) => record.clone(),
+            None => return Ok(Status::New),
+        };
+        if record.settings != self.settings
+            || record.outputs != self.relative(outputs)
+            || outputs.iter().any(|output| !output.exists())
+        {
+            return Ok(Status::Changed);
+        }
+        let (size, modified) = stat(file)?;
+        if size != record.size {
+            return Ok(Status::Changed);
+        }
+        if modified == record.modified {
+            return Ok(Status::UpToDate);
+        }
+        if hash(file)? != record.hash {
+            return Ok(Status::Changed);
+        }
+        // Touched but unchanged
+        let mut manifest = self.manifest.lock().map_err(|_| "the batch manifest is poisoned")?;
+        if let Some(record) = manifest.records.get_mut(&key) {
+            record.modified = modified;
+        }
+        Ok(Status::UpToDate)
+    }
+
+    /// Whether `output` is one of the outputs `file` was last processed into,
+    /// and so can be replaced
+    pub fn recorded(&self, file: &Path, output: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+        let key = std::path::absolute(file)?;
+        let output = output.strip_prefix(&self.dir).unwrap_or(output);
+        let manifest = self.manifest.lock().map_err(|_| "the batch manifest is poisoned")?;
+        Ok(manifest
+            .records
+            .get(&key)
+            .is_some_and(|record| record.outputs.iter().any(|recorded| recorded == output)))
+    }
+
+    /// Record that `file`, as it was in `snapshot`, was processed into
+    /// `outputs`, and save the manifest
+    pub fn record(&self, file: &Path, snapshot: Snapshot, outputs: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+        let Snapshot { size, modified, hash } = snapshot;
+        let record = Record {
+            size,
+            modified,
+            hash,
+            settings: self.settings.clone(),
+            outputs: self.relative(outputs),
+        };
+        let mut manifest = self.manifest.lock().map_err(|_| "the batch manifest is poisoned")?;
+        manifest.records.insert(std::path::absolute(file)?, record);
+        let output = PartialFile::new(&self.dir.join(MANIFEST_FILE))?;
+        std::fs::write(output.path(), serde_json::to_string_pretty(&*manifest)?)?;
+        output.persist()
+    }
+
+    /// The outputs whose input no longer exists, by input
+    pub fn orphans(&self) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        let Ok(manifest) = self.manifest.lock() else {
+            return Vec::new();
+        };
+        manifest
+            .records
+            .iter()
+            .filter(|(input, _)| !input.exists())
+            .map(|(input, record)| {
+                let outputs = record
+                    .outputs
+                    .iter()
+                    .map(|output| self.dir.join(output))
+                    .filter(|output| output.exists())
+                    .collect();
+                (input.clone(), outputs)
+            })
+            .filter(|(_, outputs): &(PathBuf, Vec<PathBuf>)| !outputs.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use clap::Parser;
+
+    use crate::{
+        incremental::{Batch, Settings, Snapshot, Status},
+        Cli, Commands,
+    };
+
+    #[test]
+    fn test_batch_status() {
+        let dir = std::env::temp_dir().join("test_batch_status");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.mp3");
+        let output = vec![dir.join("input.stem.mp4")];
+        std::fs::write(&input, b"audio").unwrap();
+        std::fs::write(&output[0], b"stem").unwrap();
+
+        let ctx = Cli::try_parse_from(["stemgen", "generate", "input.mp3", "out"]).unwrap();
+        let Commands::Generate(command) = &ctx.command else {
+            unreachable!("unexpected command value")
+        };
+        let settings = Settings::new(&ctx, command).unwrap();
+        let batch = Batch::open(&dir, settings.clone()).unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::New);
+        batch.record(&input, Snapshot::new(&input).unwrap(), &output).unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::UpToDate);
+
+        // Reloaded from the output directory
+        let batch = Batch::open(&dir, settings.clone()).unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::UpToDate);
+        std::fs::write(&input, b"other audio").unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::Changed);
+        batch.record(&input, Snapshot::new(&input).unwrap(), &output).unwrap();
+
+        let mut changed = settings.clone();
+        changed.sample_rate = 48000;
+        let batch = Batch::open(&dir, changed).unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::Changed);
+
+        // As do the cover art options
+        let ctx = Cli::try_parse_from(["stemgen", "generate", "input.mp3", "out", "--cover-type", "back"]).unwrap();
+        let Commands::Generate(command) = &ctx.command else {
+            unreachable!("unexpected command value")
+        };
+        let batch = Batch::open(&dir, Settings::new(&ctx, command).unwrap()).unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::Changed);
+
+        // Changed while processed
+        let snapshot = Snapshot::new(&input).unwrap();
+        std::fs::write(&input, b"edited audio").unwrap();
+        batch.record(&input, snapshot, &output).unwrap();
+        assert_eq!(batch.status(&input, &output).unwrap(), Status::Changed);
+
+        assert!(batch.recorded(&input, &output[0]).unwrap());
+        assert!(!batch.recorded(&input, &dir.join("other.stem.mp4")).unwrap());
+        assert!(!batch.recorded(&dir.join("other.mp3"), &output[0]).unwrap());
+
+        std::fs::remove_file(&input).unwrap();
+        assert_eq!(batch.orphans(), vec![(std::path::absolute(&input).unwrap(), output.clone())]);
+        assert_eq!(batch.status(&PathBuf::from("missing.mp3"), &output).unwrap(), Status::New);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
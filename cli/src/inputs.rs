@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use ffmpeg_next::media;
+use glob::glob;
+use stemgen::track::Track;
+
+use crate::{template::NameTemplate, utils::split_file_at_dot};
+
+/// Extensions of the audio files picked up without probing them
+const AUDIO_EXTENSIONS: [&str; 13] = [
+    "mp3", "flac", "wav", "aif", "aiff", "m4a", "aac", "alac", "ogg", "oga", "opus", "wma", "ape",
+];
+
+/// A file to process, and where to write its outputs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    pub file: PathBuf,
+    /// Directory of the outputs
+    pub dir: PathBuf,
+    /// File name of the outputs, without extension
+    pub name: String,
+}
+
+/// The files matching the glob patterns, all written to `output`
+pub fn from_globs(
+    patterns: &[String],
+    output: &Path,
+    template: Option<&NameTemplate>,
+) -> Result<Vec<Input>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let paths = glob(pattern).map_err(|err| format!("unable to render the glob: {err}"))?;
+        files.extend(paths.filter_map(Result::ok).map(|file| (file, output.to_path_buf())));
+    }
+    Ok(resolve(files, template))
+}
+
+/// The audio files found under `root`, written to the same relative
+/// directory under `output`. Stem files, hidden files and the output
+/// directory itself are skipped.
+pub fn from_tree(
+    root: &Path,
+    output: &Path,
+    ext: &str,
+    template: Option<&NameTemplate>,
+) -> Result<Vec<Input>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    walk(root, output, ext, &mut files)?;
+    let files = files
+        .into_iter()
+        .map(|file| {
+            let relative = file.parent().and_then(|dir| dir.strip_prefix(root).ok()).unwrap_or(Path::new(""));
+            let dir = output.join(relative);
+            (file, dir)
+        })
+        .collect();
+    Ok(resolve(files, template))
+}
+
+fn walk(dir: &Path, output: &Path, ext: &str, files: &mut Vec<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|err| format!("unable to read {}: {err}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    // Directory listings come in no particular order
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            if !same_file(&path, output) {
+                walk(&path, output, ext, files)?;
+            }
+        } else if !name.ends_with(&format!(".{ext}")) && is_audio(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Whether FFmpeg can decode audio from `path`, probing the files without a
+/// known audio extension
+fn is_audio(path: &Path) -> bool {
+    let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+    if extension.is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.as_str())) {
+        return true;
+    }
+    ffmpeg_next::format::input(path).is_ok_and(|input| input.streams().best(media::Type::Audio).is_some())
+}
+
+/// Name the outputs after their input, or its tags with a template, which
+/// may add sub-directories. When several inputs of the same directory would
+/// share a name, e.g. `Intro.mp3` and `Intro.flac`, their extension is added
+/// to it, then a number in the order of their path.
+fn resolve(files: Vec<(PathBuf, PathBuf)>, template: Option<&NameTemplate>) -> Vec<Input> {
+    let mut inputs: Vec<Input> = files
+        .into_iter()
+        .filter_map(|(file, dir)| {
+            let name = file.file_name().map(split_file_at_dot)?.0.to_string_lossy().into_owned();
+            let Some(template) = template else {
+                return Some(Input { file, dir, name });
+            };
+            // Unreadable inputs are reported when processed
+            let tags = Track::new(&file).map(|track| track.tags()).unwrap_or_default();
+            let path = template.render(&tags, &name);
+            let dir = match path.parent() {
+                Some(parent) => dir.join(parent),
+                None => dir,
+            };
+            let name = path.file_name()?.to_string_lossy().into_owned();
+            Some(Input { file, dir, name })
+        })
+        .collect();
+
+    let mut by_name: HashMap<(PathBuf, String), Vec<usize>> = HashMap::new();
+    for (idx, input) in inputs.iter().enumerate() {
+        // Case insensitive, as are the default file systems of macOS and Windows
+        by_name.entry((input.dir.clone(), input.name.to_lowercase())).or_default().push(idx);
+    }
+    for mut collision in by_name.into_values().filter(|idx| idx.len() > 1) {
+        collision.sort_by(|a, b| inputs[*a].file.cmp(&inputs[*b].file));
+        let mut taken: Vec<String> = Vec::new();
+        for idx in collision {
+            let input = &mut inputs[idx];
+            let ext = input.file.file_name().map(split_file_at_dot).and_then(|(_, ext)| ext);
+            let mut name = match ext {
+                Some(ext) => format!("{} ({})", input.name, ext.to_string_lossy().trim_start_matches('.')),
+                None => input.name.clone(),
+            };
+            let base = name.clone();
+            let mut count = 1;
+            while taken.contains(&name.to_lowercase()) {
+                count += 1;
+                name = format!("{base} {count}");
+            }
+            taken.push(name.to_lowercase());
+            input.name = name;
+        }
+    }
+    inputs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::inputs::{from_tree, Input};
+
+    #[test]
+    fn test_from_tree() {
+        let root = std::env::temp_dir().join("test_from_tree");
+        let _ = std::fs::remove_dir_all(&root);
+        for file in [
+            "Album A/Intro.mp3",
+            "Album A/Intro.flac",
+            "Album A/cover.jpg",
+            "Album A/.hidden.mp3",
+            "Album B/CD1/Intro.mp3",
+            "Album B/CD1/Track.stem.mp4",
+            "Single.MP3",
+            "out/Single.mp3",
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let output = root.join("out");
+        let inputs = from_tree(&root, &output, "stem.mp4", None).unwrap();
+        let input = |file: &str, dir: &str, name: &str| Input {
+            file: root.join(file),
+            dir: if dir.is_empty() { output.clone() } else { output.join(dir) },
+            name: name.to_owned(),
+        };
+        assert_eq!(
+            inputs,
+            vec![
+                input("Album A/Intro.flac", "Album A", "Intro (flac)"),
+                input("Album A/Intro.mp3", "Album A", "Intro (mp3)"),
+                input("Album B/CD1/Intro.mp3", "Album B/CD1", "Intro"),
+                input("Single.MP3", "", "Single"),
+            ]
+        );
+        assert!(from_tree(&PathBuf::from("/nonexistent"), &output, "stem.mp4", None).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,20 +1,36 @@
 use std::process::exit;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 
-use crate::cli::{Cli, Commands, prepare_ffmpeg};
+use crate::{cli::{Cli, Commands, prepare_ffmpeg}, utils::handle_interruption};
 
 mod cli;
+mod config;
 pub mod constants;
 mod create;
+mod edit;
+mod extract;
 mod generate;
+mod incremental;
+mod inputs;
+mod manifest;
+mod output;
+mod scheduler;
+mod template;
+mod utils;
+mod verify;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
+    // The raw matches tell which options were explicitly given, which `edit`
+    // needs to only change these.
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    config::apply(&mut args, &matches)?;
 
     match &args.command {
         Commands::Generate(command) => {
             prepare_ffmpeg(&args)?;
+            handle_interruption()?;
             if generate::generate(&args, command)? {
                 exit(1);
             }
@@ -22,11 +38,39 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         Commands::Create(command) => {
             prepare_ffmpeg(&args)?;
+            handle_interruption()?;
             if create::create(&args, command)? {
                 exit(1);
             }
             Ok(())
         }
+        Commands::Extract(command) => {
+            prepare_ffmpeg(&args)?;
+            handle_interruption()?;
+            if extract::extract(&args, command)? {
+                exit(1);
+            }
+            Ok(())
+        }
+        Commands::Edit(command) => {
+            if edit::edit(&args, command, &matches)? {
+                exit(1);
+            }
+            Ok(())
+        }
+        Commands::Manifest(command) => {
+            if manifest::manifest(&args, command)? {
+                exit(1);
+            }
+            Ok(())
+        }
+        Commands::Verify(command) => {
+            prepare_ffmpeg(&args)?;
+            if verify::verify(&args, command)? {
+                exit(1);
+            }
+            Ok(())
+        }
     }
 }
 
@@ -34,12 +78,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 mod tests {
     use clap::Parser;
     use stemgen::{
+        audiofile::{AudioFormat, BitDepth},
+        cover::{ImageFormat, PictureType},
         demucs::{Device, Model},
-        nistem::{Codec, Color, SampleRate},
+        multitrack::OutputFormat,
+        nistem::{Codec, Color, OpusApplication, SampleRate},
+        verify::DEFAULT_THRESHOLD,
     };
 
     use crate::{
-        cli::{Commands, CreateArgs, GenerateArgs}, Cli
+        cli::{Commands, CoverArgs, CreateArgs, EditArgs, ExtractArgs, GenerateArgs, ManifestArgs, VerifyArgs}, Cli
     };
 
     #[test]
@@ -61,15 +109,28 @@ mod tests {
                 Ok(Cli {
                     force: false,
                     verbose: false,
+                    preset: None,
                     codec: Codec::FLAC,
                     sample_rate: SampleRate::Hz44100,
                     command: Commands::Generate (GenerateArgs {
                         files,
                         output,
+                        recursive: None,
+                        name_template: None,
                         device: Device::CPU,
                         model: Model::Url(model_url),
                         thread: 4,
-                        preserved_original_as_master: false
+                        jobs: 1,
+                        sessions: 1,
+                        memory_limit: None,
+                        preserved_original_as_master: false,
+                        incremental: false,
+                        format: OutputFormat::Stem,
+                        bit_depth: None,
+                        manifest: None,
+                        verify: false,
+                        verify_threshold: _,
+                        artwork: CoverArgs { cover: None, .. },
                     }),
                     drum_stem_label,
                     bass_stem_label,
@@ -79,7 +140,15 @@ mod tests {
                     bass_stem_color: Color(0xD55E00),
                     other_stem_color: Color(0xCC79A7),
                     vocal_stem_color: Color(0x56B4E9),
-                    ext
+                    ext,
+                    loudness: false,
+                    normalize: None,
+                    replaygain: false,
+                    bitrate: None,
+                    quality: None,
+                    vbr: None,
+                    compression_level: None,
+                    opus_application: None,
                 }) if (
                     drum_stem_label == "Drums" &&
                     bass_stem_label == "Bass" &&
@@ -113,6 +182,7 @@ mod tests {
                 Ok(Cli {
                     force: false,
                     verbose: false,
+                    preset: None,
                     codec: Codec::AAC,
                     sample_rate: SampleRate::Hz44100,
                     command: Commands::Create (CreateArgs {
@@ -133,7 +203,15 @@ mod tests {
                     bass_stem_color: Color(0xD55E00),
                     other_stem_color: Color(0xCC79A7),
                     vocal_stem_color: Color(0x56B4E9),
-                    ext
+                    ext,
+                    loudness: false,
+                    normalize: None,
+                    replaygain: false,
+                    bitrate: None,
+                    quality: None,
+                    vbr: None,
+                    compression_level: None,
+                    opus_application: None,
                 }) if (
                     drum_stem_label == "Drums" &&
                     bass_stem_label == "Bass" &&
@@ -179,6 +257,7 @@ mod tests {
                 Ok(Cli {
                     force: false,
                     verbose: false,
+                    preset: None,
                     codec: Codec::AAC,
                     sample_rate: SampleRate::Hz44100,
                     command: Commands::Create (CreateArgs {
@@ -199,7 +278,15 @@ mod tests {
                     bass_stem_color: Color(0x656bba),
                     other_stem_color: Color(0x52d034),
                     vocal_stem_color: Color(0xdaae2a),
-                    ext
+                    ext,
+                    loudness: false,
+                    normalize: None,
+                    replaygain: false,
+                    bitrate: None,
+                    quality: None,
+                    vbr: None,
+                    compression_level: None,
+                    opus_application: None,
                 }) if (
                     drum_stem_label == "Kick" &&
                     bass_stem_label == "SubBass" &&
@@ -217,4 +304,347 @@ mod tests {
             "Expected value to match pattern, but got: {ctx:?}"
         );
     }
+
+    #[test]
+    fn test_generate_command_with_loudness() {
+        let arg_vec = vec![
+            "stemgen",
+            "generate",
+            "./my_file.mp3",
+            "~/MyMusic",
+            "--normalize",
+            "-14",
+            "--replaygain",
+        ];
+        let ctx = Cli::try_parse_from(arg_vec);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    loudness: false,
+                    normalize: Some(target),
+                    replaygain: true,
+                    ..
+                }) if *target == -14.0
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+    }
+
+    #[test]
+    fn test_generate_command_with_encoder_options() {
+        let arg_vec = vec![
+            "stemgen",
+            "generate",
+            "./my_file.mp3",
+            "~/MyMusic",
+            "--codec",
+            "opus",
+            "--bitrate",
+            "192k",
+            "--vbr",
+            "false",
+            "--compression-level",
+            "10",
+            "--opus-application",
+            "lowdelay",
+        ];
+        let ctx = Cli::try_parse_from(arg_vec);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    bitrate: Some(192_000),
+                    quality: None,
+                    vbr: Some(false),
+                    compression_level: Some(10),
+                    opus_application: Some(OpusApplication::LowDelay),
+                    ..
+                })
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--bitrate", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_extract_command() {
+        let arg_vec = vec![
+            "stemgen",
+            "extract",
+            "Artist - Title.stem.mp4",
+            "~/MyStems",
+            "--format",
+            "flac",
+            "--bit-depth",
+            "24",
+            "--copy-tags",
+        ];
+        let ctx = Cli::try_parse_from(arg_vec);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Extract(ExtractArgs {
+                        input,
+                        output,
+                        format: AudioFormat::FLAC,
+                        bit_depth: BitDepth::Int24,
+                        copy_tags: true,
+                    }),
+                    ..
+                }) if (
+                    input.display().to_string() == "Artist - Title.stem.mp4" &&
+                    output.display().to_string() == "~/MyStems"
+                )
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+    }
+
+    #[test]
+    fn test_edit_command() {
+        let arg_vec = vec![
+            "stemgen",
+            "edit",
+            "Artist - Title.stem.mp4",
+            "--vocal-stem-label",
+            "Voices",
+            "--mastering-dsp",
+            "limiter.enabled=true",
+            "--mastering-dsp",
+            "compressor.ratio=4",
+            "--genre",
+            "",
+            "--dry-run",
+        ];
+        let ctx = Cli::try_parse_from(arg_vec);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Edit(EditArgs {
+                        file,
+                        mastering_dsp,
+                        title: None,
+                        genre: Some(genre),
+                        cover: None,
+                        remove_cover: false,
+                        dry_run: true,
+                        ..
+                    }),
+                    vocal_stem_label,
+                    ..
+                }) if (
+                    file.display().to_string() == "Artist - Title.stem.mp4" &&
+                    *mastering_dsp == ["limiter.enabled=true", "compressor.ratio=4"] &&
+                    genre.is_empty() &&
+                    vocal_stem_label == "Voices"
+                )
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+    }
+
+    #[test]
+    fn test_create_command_with_format() {
+        let arg_vec = vec![
+            "stemgen", "create",
+            "--mastered", "Pre-mastered mix.mp3",
+            "--drum", "drum part.mp3",
+            "--bass", "bass part.mp3",
+            "--other", "other part.mp3",
+            "--vocal", "vocal part.mp3",
+            "--format", "multichannel-wav",
+            "--bit-depth", "24",
+            "Artist - Title.wav"
+        ];
+        let ctx = Cli::try_parse_from(arg_vec);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Create(CreateArgs {
+                        format: OutputFormat::Multichannel(AudioFormat::WAV),
+                        bit_depth: Some(BitDepth::Int24),
+                        ..
+                    }),
+                    ..
+                })
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+    }
+
+    #[test]
+    fn test_high_sample_rate() {
+        let ctx = Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--sample-rate", "96000"]);
+        assert!(
+            matches!(&ctx, Ok(Cli { sample_rate: SampleRate::Hz96000, .. })),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        let ctx = Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--sample-rate", "32000"]);
+        assert!(
+            matches!(&ctx, Ok(Cli { sample_rate: SampleRate::Other(32000), .. })),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--sample-rate", "96k"]).is_err());
+    }
+
+    #[test]
+    fn test_manifest_command() {
+        let ctx = Cli::try_parse_from(vec!["stemgen", "manifest", "Artist - Title.stem.mp4"]);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Manifest(ManifestArgs { file }),
+                    ..
+                }) if file.display().to_string() == "Artist - Title.stem.mp4"
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+    }
+
+    #[test]
+    fn test_create_command_with_manifest() {
+        let mut arg_vec = vec![
+            "stemgen", "create",
+            "--mastered", "Pre-mastered mix.mp3",
+            "--drum", "drum part.mp3",
+            "--bass", "bass part.mp3",
+            "--other", "other part.mp3",
+            "--vocal", "vocal part.mp3",
+            "--manifest", "stem.json",
+            "Artist - Title.stem.mp4"
+        ];
+        let ctx = Cli::try_parse_from(arg_vec.clone());
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Create(CreateArgs {
+                        manifest: Some(manifest),
+                        ..
+                    }),
+                    ..
+                }) if manifest.display().to_string() == "stem.json"
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        // The labels and colours come from the manifest only
+        arg_vec.extend(["--drum-stem-label", "Kick"]);
+        assert!(Cli::try_parse_from(arg_vec).is_err());
+    }
+
+    #[test]
+    fn test_generate_command_recursive() {
+        let ctx = Cli::try_parse_from(vec!["stemgen", "generate", "--recursive", "~/Music", "~/Stems"]);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Generate(GenerateArgs {
+                        files,
+                        output,
+                        recursive: Some(root),
+                        ..
+                    }),
+                    ..
+                }) if files.is_empty() && output.display().to_string() == "~/Stems" && root.display().to_string() == "~/Music"
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "~/Stems"]).is_err());
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "~/Stems", "--recursive", "~/Music"]).is_err());
+        assert!(matches!(
+            Cli::try_parse_from(vec!["stemgen", "generate", "--recursive", "~/Music", "~/Stems", "--jobs", "3"]),
+            Ok(Cli { command: Commands::Generate(GenerateArgs { jobs: 3, .. }), .. })
+        ));
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "~/Stems", "--jobs", "0"]).is_err());
+        assert!(matches!(
+            Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "~/Stems", "--sessions", "2", "--memory-limit", "512M"]),
+            Ok(Cli { command: Commands::Generate(GenerateArgs { sessions: 2, memory_limit: Some(536870912), .. }), .. })
+        ));
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "~/Stems", "--memory-limit", "lots"]).is_err());
+        assert!(matches!(
+            Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "~/Stems", "--skip-existing"]),
+            Ok(Cli { command: Commands::Generate(GenerateArgs { incremental: true, .. }), .. })
+        ));
+    }
+
+    #[test]
+    fn test_generate_command_with_name_template() {
+        let ctx = Cli::try_parse_from(vec![
+            "stemgen", "generate", "a.mp3", "out",
+            "--name-template", "{artist}/{trackno:02} - {title}",
+        ]);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Generate(GenerateArgs { name_template: Some(_), .. }),
+                    ..
+                })
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--name-template", "{year}"]).is_err());
+    }
+
+    #[test]
+    fn test_generate_command_with_cover() {
+        let ctx = Cli::try_parse_from(vec![
+            "stemgen", "generate", "a.mp3", "out",
+            "--cover", "artwork.jpg",
+            "--cover-max-size", "600",
+            "--cover-format", "png",
+            "--cover-type", "back",
+        ]);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Generate(GenerateArgs {
+                        artwork: CoverArgs {
+                            cover: Some(cover),
+                            cover_max_size: Some(600),
+                            cover_format: Some(ImageFormat::PNG),
+                            cover_type: PictureType::BackCover,
+                        },
+                        ..
+                    }),
+                    ..
+                }) if cover.display().to_string() == "artwork.jpg"
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        assert!(Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--cover-format", "gif"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_command() {
+        let ctx = Cli::try_parse_from(vec!["stemgen", "verify", "--threshold", "-40", "--json", "Artist - Title.stem.mp4"]);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Verify(VerifyArgs { file, threshold, json: true }),
+                    ..
+                }) if file.display().to_string() == "Artist - Title.stem.mp4" && *threshold == -40.0
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+        let ctx = Cli::try_parse_from(vec!["stemgen", "generate", "a.mp3", "out", "--verify"]);
+        assert!(
+            matches!(
+                &ctx,
+                Ok(Cli {
+                    command: Commands::Generate(GenerateArgs { verify: true, verify_threshold, .. }),
+                    ..
+                }) if *verify_threshold == DEFAULT_THRESHOLD
+            ),
+            "Expected value to match pattern, but got: {ctx:?}"
+        );
+    }
 }
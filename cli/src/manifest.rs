@@ -0,0 +1,9 @@
+use stemgen::editor::NIStemEditor;
+
+use crate::cli::{Cli, ManifestArgs};
+
+pub fn manifest(_ctx: &Cli, command: &ManifestArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    let editor = NIStemEditor::open(&command.file)?;
+    println!("{}", editor.manifest().to_json()?);
+    Ok(false)
+}
@@ -0,0 +1,253 @@
+use std::path::{Path, PathBuf};
+
+use ffmpeg_next::{codec, Packet, Rational};
+use indicatif::MultiProgress;
+use stemgen::{
+    audiofile::{AudioFormat, BitDepth},
+    buffer::AudioBuffer,
+    cover::{self, CoverOptions},
+    loudness::Loudness,
+    multitrack::{Multitrack, OutputFormat},
+    nistem::{self, Codec, EncoderOptions, NIStem},
+    track::{Gapless, Track},
+};
+
+use crate::{cli::{Cli, Commands, CoverArgs}, utils::sanitize_filename};
+
+/// The manifest describing the stems, loaded from `--manifest` or as
+/// configured on the command line
+pub fn manifest(ctx: &Cli) -> Result<nistem::Atom, Box<dyn std::error::Error>> {
+    let file = match &ctx.command {
+        Commands::Generate(command) => command.manifest.as_ref(),
+        Commands::Create(command) => command.manifest.as_ref(),
+        _ => None,
+    };
+    if let Some(file) = file {
+        return nistem::Atom::from_file(file);
+    }
+    let manifest = nistem::Atom {
+        stems: [
+            nistem::AtomStem {
+                color: ctx.drum_stem_color.to_owned(),
+                name: ctx.drum_stem_label.to_owned(),
+            },
+            nistem::AtomStem {
+                color: ctx.bass_stem_color.to_owned(),
+                name: ctx.bass_stem_label.to_owned(),
+            },
+            nistem::AtomStem {
+                color: ctx.other_stem_color.to_owned(),
+                name: ctx.other_stem_label.to_owned(),
+            },
+            nistem::AtomStem {
+                color: ctx.vocal_stem_color.to_owned(),
+                name: ctx.vocal_stem_label.to_owned(),
+            },
+        ],
+        version: 1,
+        ..Default::default()
+    };
+    manifest.validate()?;
+    Ok(manifest)
+}
+
+/// The files written for `filename` in `dir`: the stem file, each stream
+/// named after its label, or the multichannel file.
+pub fn output_paths(
+    ctx: &Cli,
+    format: OutputFormat,
+    dir: &Path,
+    filename: &str,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    Ok(match format {
+        OutputFormat::Stem => vec![dir.join(format!("{filename}.{}", ctx.ext))],
+        OutputFormat::Files(format) => std::iter::once("Master".to_owned())
+            .chain(manifest(ctx)?.stems.into_iter().map(|stem| stem.name))
+            .map(|label| dir.join(format!("{filename} - {}.{}", sanitize_filename(&label), format.extension())))
+            .collect(),
+        OutputFormat::Multichannel(format) => vec![dir.join(format!("{filename}.{}", format.extension()))],
+    })
+}
+
+/// Options only supported when writing a NI stem file
+pub fn check_format(ctx: &Cli, format: OutputFormat, preserved_original: bool) -> Result<(), String> {
+    if format == OutputFormat::Stem {
+        return Ok(());
+    }
+    if preserved_original {
+        return Err(format!("the original cannot be preserved as master with the {format} format"));
+    }
+    if ctx.loudness || ctx.replaygain || ctx.normalize.is_some() {
+        return Err(format!("loudness options are not supported with the {format} format"));
+    }
+    Ok(())
+}
+
+/// Whether the original master of `file` can be stored as is, reporting the
+/// path taken through `progress`. Masters which can't are re-encoded with the
+/// stem codec.
+pub fn preserve_original(ctx: &Cli, file: &Path, input: &Track, progress: &MultiProgress) -> bool {
+    let id = input.args().0.id();
+    match nistem::check_preservable(id) {
+        Ok(()) => {
+            progress.suspend(|| println!("{}: preserving the original {id:?} master", file.display()));
+            true
+        }
+        Err(err) => {
+            progress.suspend(|| println!("{}: {err}, re-encoding the master with {}", file.display(), ctx.codec));
+            false
+        }
+    }
+}
+
+/// The highest bit depth of the inputs, when the output format can store
+/// it. Inputs above 24 bits are kept as 24 bits by ALAC and FLAC.
+pub fn preserved_bit_depth(ctx: &Cli, format: OutputFormat, inputs: &[Track]) -> Option<BitDepth> {
+    let depth = inputs.iter().filter_map(Track::bit_depth).max()?;
+    let integer_only = match format {
+        OutputFormat::Stem => match ctx.codec {
+            Codec::ALAC | Codec::FLAC => true,
+            Codec::AAC | Codec::OPUS => return None,
+        },
+        OutputFormat::Files(format) | OutputFormat::Multichannel(format) => format == AudioFormat::FLAC,
+    };
+    Some(if integer_only { depth.min(BitDepth::Int24) } else { depth })
+}
+
+/// The sample rate of the output, the closest one supported by the codec of
+/// the stem file when the requested one isn't.
+pub fn sample_rate(ctx: &Cli, format: OutputFormat) -> Result<u32, String> {
+    match format {
+        OutputFormat::Stem => Ok(ctx.sample_rate.supported_by(ctx.codec.into())?.into()),
+        _ => Ok(ctx.sample_rate.into()),
+    }
+}
+
+pub enum Output {
+    Stem(NIStem),
+    Multitrack(Multitrack),
+}
+
+impl Output {
+    pub fn new(
+        ctx: &Cli,
+        format: OutputFormat,
+        paths: &[PathBuf],
+        original: Option<(codec::Parameters, Rational, Gapless)>,
+        depth: Option<BitDepth>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let manifest = manifest(ctx)?;
+        let sample_rate = self::sample_rate(ctx, format)?;
+        if sample_rate != u32::from(ctx.sample_rate) {
+            eprintln!(
+                "{} doesn't support {}, using {sample_rate} Hz instead",
+                ctx.codec, ctx.sample_rate
+            );
+        }
+        let stem = (codec::Id::from(ctx.codec), sample_rate as i32);
+        let options = EncoderOptions {
+            bit_depth: depth,
+            ..ctx.into()
+        };
+        Ok(match (format, original) {
+            (OutputFormat::Stem, Some(original)) => {
+                Output::Stem(NIStem::new_with_preserved_original(&paths[0], original, stem, options)?)
+            }
+            (OutputFormat::Stem, None) => {
+                let mut nistem = NIStem::new_with_consistent_streams(&paths[0], stem, options)?;
+                nistem.render_master(&manifest.mastering_dsp)?;
+                Output::Stem(nistem)
+            }
+            (OutputFormat::Files(format), _) => Output::Multitrack(Multitrack::new_with_files(
+                paths,
+                format,
+                depth.unwrap_or_default(),
+                sample_rate,
+            )?),
+            (OutputFormat::Multichannel(format), _) => Output::Multitrack(Multitrack::new_multichannel(
+                &paths[0],
+                format,
+                depth.unwrap_or_default(),
+                sample_rate,
+            )?),
+        })
+    }
+
+    pub fn is_preserved(&self) -> bool {
+        matches!(self, Output::Stem(NIStem::PreservedMaster(..)))
+    }
+
+    /// Copy the tags and cover art of `path`, reporting the tags which can't
+    /// be stored in the output
+    pub fn clone(&mut self, path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Output::Stem(nistem) => {
+                nistem.clone(path)?;
+                for tag in nistem.unsupported_tags() {
+                    eprintln!("{}: {tag} tags can't be stored in a NI stem file and are dropped", path.display());
+                }
+                Ok(())
+            }
+            Output::Multitrack(multitrack) => {
+                multitrack.clone(path);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the cover art following `artwork`: the given image, the cover art
+    /// copied from the input, or else the one found next to `input`.
+    pub fn apply_cover(&mut self, artwork: &CoverArgs, input: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let options = CoverOptions::from(artwork);
+        let existing = match self {
+            Output::Stem(nistem) => nistem.cover().to_vec(),
+            Output::Multitrack(multitrack) => multitrack.cover().to_vec(),
+        };
+        let cover = match (&artwork.cover, existing.is_empty()) {
+            (Some(image), _) => vec![cover::from_file_with(image, &options)?],
+            (None, false) => existing
+                .into_iter()
+                .map(|picture| cover::convert(picture, &options))
+                .collect::<Result<_, _>>()?,
+            (None, true) => match cover::find_next_to(input) {
+                Some(image) => vec![cover::from_file_with(&image, &options)?],
+                None => return Ok(()),
+            },
+        };
+        match self {
+            Output::Stem(nistem) => nistem.set_cover(cover),
+            Output::Multitrack(multitrack) => multitrack.set_cover(cover),
+        }
+        Ok(())
+    }
+
+    pub fn analyze_master(&mut self, samples: &AudioBuffer) {
+        if let Output::Stem(nistem) = self {
+            nistem.analyze_master(samples);
+        }
+    }
+
+    /// Write the master followed by the stems, or only the stems along with
+    /// the original packets when preserving the master.
+    pub fn write(&mut self, original: Vec<Packet>, data: Vec<AudioBuffer>) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Output::Stem(nistem @ NIStem::PreservedMaster(..)) => nistem.write_preserved(original, data),
+            Output::Stem(nistem) => nistem.write_consistent(data),
+            Output::Multitrack(multitrack) => multitrack.write(data),
+        }
+    }
+
+    pub fn loudness(&self) -> Option<Vec<Loudness>> {
+        match self {
+            Output::Stem(nistem) => nistem.loudness(),
+            Output::Multitrack(_) => None,
+        }
+    }
+
+    pub fn flush(self, ctx: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Output::Stem(nistem) => nistem.flush(manifest(ctx)?),
+            Output::Multitrack(multitrack) => multitrack.flush(),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::{Condvar, Mutex};
+
+use stemgen::demucs;
+
+/// Rough memory held by a job while a segment is in flight: the decoded
+/// samples, the model input and output, and the copies handed to the
+/// encoders, all as 32 bits floats.
+pub const SEGMENT_MEMORY: usize = 32 * demucs::SEGMENT_LENGTH * std::mem::size_of::<f32>();
+
+/// A counting semaphore over a memory budget, in bytes, shared by the jobs
+#[derive(Debug)]
+pub struct Budget {
+    capacity: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Budget {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            available: Mutex::new(capacity),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until `amount` is available. Requests above the whole budget are
+    /// capped to it, so that they run on their own rather than never.
+    pub fn acquire(&self, amount: usize) -> Permit<'_> {
+        let amount = amount.min(self.capacity);
+        let mut available = self.available.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while *available < amount {
+            available = self
+                .released
+                .wait(available)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *available -= amount;
+        Permit { budget: self, amount }
+    }
+}
+
+/// Memory taken from a `Budget`, given back when dropped
+#[derive(Debug)]
+pub struct Permit<'a> {
+    budget: &'a Budget,
+    amount: usize,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut available = self.budget.available.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *available += self.amount;
+        self.budget.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use crate::scheduler::Budget;
+
+    #[test]
+    fn test_budget() {
+        let budget = Budget::new(2);
+        // Capped to the budget
+        drop(budget.acquire(5));
+
+        let first = budget.acquire(1);
+        let second = budget.acquire(1);
+        let acquired = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _permit = budget.acquire(2);
+                acquired.store(true, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::SeqCst));
+            drop(first);
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::SeqCst));
+            drop(second);
+        });
+        assert!(acquired.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use stemgen::constant::{Metadata, MetadataValue};
+
+use crate::utils::sanitize_filename;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Tag(Metadata),
+    /// The name of the input, without extension
+    Filename,
+}
+
+impl TryFrom<&str> for Field {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "title" => Ok(Self::Tag(Metadata::Title)),
+            "artist" => Ok(Self::Tag(Metadata::Artist)),
+            "release" | "album" => Ok(Self::Tag(Metadata::Release)),
+            "label" => Ok(Self::Tag(Metadata::Label)),
+            "genre" => Ok(Self::Tag(Metadata::Genre)),
+            "trackno" | "track" => Ok(Self::Tag(Metadata::TrackNo)),
+            "filename" => Ok(Self::Filename),
+            _ => Err(format!(
+                "unknown field {{{value}}}, expected one of title, artist, release, label, genre, trackno or filename"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field {
+        field: Field,
+        /// Zero padding of numbers, e.g. 2 for `{trackno:02}`
+        width: Option<usize>,
+        fallback: Option<String>,
+    },
+}
+
+/// Names the outputs after the tags of their input, such as
+/// `{artist}/{release}/{trackno:02} - {title}`. Each `/` adds a
+/// sub-directory. A missing tag is replaced by the fallback given after `|`,
+/// e.g. `{artist|Various}`, or else by the file name for the title and
+/// "Unknown ..." for the others.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    /// As given, e.g. to record it
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl TryFrom<&str> for NameTemplate {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if value.trim().is_empty() {
+            return Err("the name template must not be empty".to_owned());
+        }
+        if value.starts_with('/') {
+            return Err("the name template must be relative to the output directory".to_owned());
+        }
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let (spec, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or(format!("unclosed field in {value}"))?;
+                    chars = rest.chars();
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(parse_field(spec)?);
+                }
+                '}' => return Err(format!("unexpected }} in {value}, use }}}} for a literal one")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self {
+            template: value.to_owned(),
+            parts,
+        })
+    }
+}
+
+impl std::fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+fn parse_field(spec: &str) -> Result<Part, String> {
+    let (spec, fallback) = match spec.split_once('|') {
+        Some((spec, fallback)) => (spec, Some(fallback.to_owned())),
+        None => (spec, None),
+    };
+    let (name, width) = match spec.split_once(':') {
+        Some((name, width)) => {
+            let width = width
+                .strip_prefix('0')
+                .and_then(|width| width.parse().ok())
+                .ok_or(format!("invalid format {{{spec}}}, only zero padding such as {{trackno:02}} is supported"))?;
+            (name, Some(width))
+        }
+        None => (spec, None),
+    };
+    Ok(Part::Field {
+        field: Field::try_from(name.trim())?,
+        width,
+        fallback,
+    })
+}
+
+impl NameTemplate {
+    /// The output path, relative to the output directory and without
+    /// extension, of an input named `filename` with `tags`
+    pub fn render(&self, tags: &HashMap<Metadata, MetadataValue>, filename: &str) -> PathBuf {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Field { field, width, fallback } => {
+                    let value = match field {
+                        Field::Tag(tag) => tags.get(tag).map(MetadataValue::to_string),
+                        Field::Filename => Some(filename.to_owned()),
+                    };
+                    // Values can't add sub-directories
+                    let value = value
+                        .map(|value| sanitize_filename(&value))
+                        .filter(|value| !value.is_empty())
+                        .or_else(|| fallback.clone())
+                        .unwrap_or_else(|| default(*field, filename));
+                    match *width {
+                        Some(width) if value.bytes().all(|c| c.is_ascii_digit()) => {
+                            rendered.push_str(&format!("{value:0>width$}"))
+                        }
+                        _ => rendered.push_str(&value),
+                    }
+                }
+            }
+        }
+        // Dropping empty segments also drops `.` and `..`, whose dots are trimmed
+        let path: PathBuf = rendered
+            .split('/')
+            .map(sanitize_filename)
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        match path.file_name() {
+            Some(_) => path,
+            None => PathBuf::from(sanitize_filename(filename)),
+        }
+    }
+}
+
+fn default(field: Field, filename: &str) -> String {
+    match field {
+        Field::Tag(Metadata::Title) | Field::Filename => filename.to_owned(),
+        Field::Tag(Metadata::TrackNo) => "0".to_owned(),
+        Field::Tag(tag) => format!("Unknown {tag}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use stemgen::constant::{Metadata, MetadataValue};
+
+    use crate::template::NameTemplate;
+
+    #[test]
+    fn test_name_template() {
+        let template = NameTemplate::try_from("{artist}/{release}/{trackno:02} - {title}").unwrap();
+        let mut tags = HashMap::from([
+            (Metadata::Artist, MetadataValue::String("AC/DC".to_owned())),
+            (Metadata::Release, MetadataValue::String("Back in Black".to_owned())),
+            (Metadata::TrackNo, MetadataValue::Number(6)),
+            (Metadata::Title, MetadataValue::String("Back in Black?".to_owned())),
+        ]);
+        assert_eq!(
+            template.render(&tags, "06 Back in Black"),
+            PathBuf::from("AC_DC/Back in Black/06 - Back in Black_")
+        );
+
+        tags.remove(&Metadata::Release);
+        tags.remove(&Metadata::Title);
+        assert_eq!(
+            template.render(&tags, "06 Back in Black"),
+            PathBuf::from("AC_DC/Unknown Release/06 - 06 Back in Black")
+        );
+        let template = NameTemplate::try_from("{artist|Various}/{{{genre}}}").unwrap();
+        assert_eq!(template.render(&HashMap::new(), "Intro"), PathBuf::from("Various/{Unknown Genre}"));
+        let template = NameTemplate::try_from("../{title}").unwrap();
+        assert_eq!(template.render(&HashMap::new(), "Intro"), PathBuf::from("Intro"));
+
+        assert!(NameTemplate::try_from("").is_err());
+        assert!(NameTemplate::try_from("/{title}").is_err());
+        assert!(NameTemplate::try_from("{year} - {title}").is_err());
+        assert!(NameTemplate::try_from("{trackno:2} - {title}").is_err());
+        assert!(NameTemplate::try_from("{title").is_err());
+        assert!(NameTemplate::try_from("title}").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{ffi::OsStr, path::Path};
+
+use clap::{parser::ValueSource, ArgMatches};
+use stemgen::{
+    loudness::{self, Loudness, MAX_TRUE_PEAK},
+    nistem::NIStem,
+    partial,
+    track::Track,
+    verify::Verification,
+};
+
+use crate::cli::Cli;
+
+/// Remove the incomplete outputs and exit on SIGINT or SIGTERM. Existing
+/// files are only replaced once the new ones are complete, so they are left
+/// untouched.
+pub fn handle_interruption() -> Result<(), Box<dyn std::error::Error>> {
+    ctrlc::set_handler(|| {
+        eprintln!("\nInterrupted, removing incomplete outputs");
+        partial::remove_pending();
+        std::process::exit(130);
+    })?;
+    Ok(())
+}
+
+/// Whether an option was given on the command line, rather than its default
+/// value being used.
+pub fn is_set(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+        || matches.subcommand().is_some_and(|(_, matches)| is_set(matches, id))
+}
+
+pub fn split_file_at_dot(file: &OsStr) -> (&OsStr, Option<&OsStr>) {
+    let slice = file.as_encoded_bytes();
+    if slice == b".." {
+        return (file, None);
+    }
+
+    let mut current_idx = slice.len();
+
+    while current_idx > 0 {
+        match slice[1..current_idx].iter().rposition(|b| *b == b'.') {
+            Some(i) =>  {
+                let ext = &slice[i+1..].to_ascii_lowercase();
+                if !ext.iter().all(|c|(*c >= b'0' && *c <= b'9') || (*c >= b'a' && *c <= b'z') || *c == b'.') {
+                    break;
+                }
+                current_idx = i + 1;
+            },
+            None => break,
+        };
+    }
+
+    if current_idx == slice.len() {
+        return (file, None)
+    }
+
+    let before = &slice[..current_idx];
+    let after = &slice[current_idx..];
+    unsafe {
+        (
+            OsStr::from_encoded_bytes_unchecked(before),
+            Some(OsStr::from_encoded_bytes_unchecked(after)),
+        )
+    }
+}
+
+pub fn enable_loudness(ctx: &Cli, nistem: &mut NIStem) {
+    if ctx.loudness || ctx.replaygain || ctx.normalize.is_some() {
+        nistem.enable_loudness(ctx.replaygain);
+    }
+}
+
+/// Apply the gain normalizing `file` to `target` LUFS, returning a warning
+/// when its true peak keeps it from reaching the target
+pub fn normalize(nistem: &mut NIStem, file: &Path, target: f64) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let loudness = loudness::analyze(&mut Track::new(file)?)?;
+    let gain = loudness.normalization(target);
+    nistem.set_gain(10f64.powf(gain / 20.0) as f32)?;
+    Ok((gain < target - loudness.integrated).then(|| {
+        format!(
+            "{}: normalized to {:.1} LUFS rather than {target:.1} LUFS to keep the true peak below {MAX_TRUE_PEAK:.1} dBTP",
+            file.display(),
+            loudness.integrated + gain
+        )
+    }))
+}
+
+pub fn print_loudness(ctx: &Cli, output: &Path, loudness: &[Loudness]) {
+    let labels = [
+        "Master",
+        &ctx.drum_stem_label,
+        &ctx.bass_stem_label,
+        &ctx.other_stem_label,
+        &ctx.vocal_stem_label,
+    ];
+    println!("Loudness of {}:", output.display());
+    for (label, loudness) in labels.iter().zip(loudness) {
+        println!("  {label}: {loudness}");
+    }
+}
+
+pub fn print_verification(verification: &Verification) {
+    println!(
+        "Verification of {}: {}",
+        verification.file.display(),
+        if verification.passed { "passed" } else { "failed" }
+    );
+    println!(
+        "  Residual: {:.1} dBFS RMS (threshold {:.1} dBFS), {:.1} dBFS peak",
+        verification.rms_residual, verification.threshold, verification.peak_residual
+    );
+    for (idx, stream) in verification.streams.iter().enumerate() {
+        println!(
+            "  Stream {idx}: {} channels, {} Hz, {:.3}s",
+            stream.channels, stream.sample_rate, stream.duration
+        );
+    }
+    for issue in &verification.issues {
+        println!("  Issue: {issue}");
+    }
+}
+
+/// Make a stem label safe to use in a file name.
+pub fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.trim().trim_end_matches('.').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::sanitize_filename;
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("Drums"), "Drums");
+        assert_eq!(sanitize_filename("AC/DC: Vocals?"), "AC_DC_ Vocals_");
+        assert_eq!(sanitize_filename(" Synths. "), "Synths");
+    }
+}
@@ -0,0 +1,13 @@
+use stemgen::verify;
+
+use crate::{cli::{Cli, VerifyArgs}, utils::print_verification};
+
+pub fn verify(_ctx: &Cli, command: &VerifyArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    let verification = verify::verify(&command.file, command.threshold)?;
+    if command.json {
+        println!("{}", verification.to_json()?);
+    } else {
+        print_verification(&verification);
+    }
+    Ok(!verification.passed)
+}
@@ -0,0 +1,363 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use ffmpeg_next::{
+    codec::{self, Compliance}, encoder, format::{self, context}, frame::Audio, software::resampling, ChannelLayout, Packet
+};
+use taglib::AttachedPicture;
+
+use crate::{buffer::{AudioBuffer, Layout}, constant::{Metadata, MetadataValue}, nistem::{converter, write_tags}, partial::PartialFile};
+
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    #[default]
+    WAV,
+    FLAC,
+    AIFF,
+}
+
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BitDepth {
+    #[default]
+    Int16,
+    Int24,
+    /// Requested as `32i`, `32` being floating point
+    Int32,
+    Float32,
+}
+
+impl TryFrom<&str> for AudioFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "wav" => Ok(AudioFormat::WAV),
+            "flac" => Ok(AudioFormat::FLAC),
+            "aiff" => Ok(AudioFormat::AIFF),
+            _ => Err("unknown or unsupported format".to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl AudioFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::WAV => "wav",
+            AudioFormat::FLAC => "flac",
+            AudioFormat::AIFF => "aiff",
+        }
+    }
+
+    fn codec(&self, depth: BitDepth) -> Result<(codec::Id, format::Sample), String> {
+        let packed = format::sample::Type::Packed;
+        match (self, depth) {
+            (AudioFormat::WAV, BitDepth::Int16) => Ok((codec::Id::PCM_S16LE, format::Sample::I16(packed))),
+            (AudioFormat::WAV, BitDepth::Int24) => Ok((codec::Id::PCM_S24LE, format::Sample::I32(packed))),
+            (AudioFormat::WAV, BitDepth::Int32) => Ok((codec::Id::PCM_S32LE, format::Sample::I32(packed))),
+            (AudioFormat::WAV, BitDepth::Float32) => Ok((codec::Id::PCM_F32LE, format::Sample::F32(packed))),
+            (AudioFormat::AIFF, BitDepth::Int16) => Ok((codec::Id::PCM_S16BE, format::Sample::I16(packed))),
+            (AudioFormat::AIFF, BitDepth::Int24) => Ok((codec::Id::PCM_S24BE, format::Sample::I32(packed))),
+            (AudioFormat::AIFF, BitDepth::Int32) => Ok((codec::Id::PCM_S32BE, format::Sample::I32(packed))),
+            (AudioFormat::AIFF, BitDepth::Float32) => Ok((codec::Id::PCM_F32BE, format::Sample::F32(packed))),
+            (AudioFormat::FLAC, BitDepth::Int16) => Ok((codec::Id::FLAC, format::Sample::I16(packed))),
+            (AudioFormat::FLAC, BitDepth::Int24) => Ok((codec::Id::FLAC, format::Sample::I32(packed))),
+            (AudioFormat::FLAC, BitDepth::Int32) => Err("FLAC files support at most 24 bits".to_owned()),
+            (AudioFormat::FLAC, BitDepth::Float32) => Err("FLAC doesn't support floating point samples".to_owned()),
+        }
+    }
+}
+
+impl TryFrom<&str> for BitDepth {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "16" => Ok(BitDepth::Int16),
+            "24" => Ok(BitDepth::Int24),
+            "32" => Ok(BitDepth::Float32),
+            "32i" => Ok(BitDepth::Int32),
+            _ => Err("unsupported bit depth".to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for BitDepth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitDepth::Int16 => write!(f, "16"),
+            BitDepth::Int24 => write!(f, "24"),
+            BitDepth::Int32 => write!(f, "32i"),
+            BitDepth::Float32 => write!(f, "32"),
+        }
+    }
+}
+
+/// Plain audio file (WAV, FLAC or AIFF) written from `AudioBuffer`s.
+pub struct AudioFileWriter {
+    ctx: context::Output,
+    /// Dropped after `ctx`, removing the file unless flushed successfully
+    output: PartialFile,
+    encoder: encoder::Audio,
+    /// Converts to the output sample rate, set up with the rate of the first buffer
+    resampler: Option<resampling::Context>,
+    /// Converts to the sample format of the encoder
+    converter: resampling::Context,
+    channels: usize,
+    input_rate: Option<u32>,
+    /// Samples at the output sample rate, not yet making a complete frame
+    overrun: Vec<f32>,
+    timestamp: i64,
+    metadata: HashMap<Metadata, MetadataValue>,
+    cover: Vec<AttachedPicture>,
+}
+
+pub(crate) fn packed_frame(samples: &[f32], channels: usize, layout: ChannelLayout) -> Audio {
+    let mut frame = Audio::new(
+        format::Sample::F32(format::sample::Type::Packed),
+        samples.len(),
+        layout,
+    );
+    frame.plane_mut(0).copy_from_slice(samples);
+    frame.set_samples(samples.len() / channels);
+    frame
+}
+
+pub(crate) fn packed_samples(mut frame: Audio, channels: usize) -> Vec<f32> {
+    frame.set_samples(frame.samples() * channels);
+    frame.plane(0).to_vec()
+}
+
+impl AudioFileWriter {
+    pub fn new(
+        path: &PathBuf,
+        format: AudioFormat,
+        depth: BitDepth,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        ffmpeg_next::init()?;
+        let (id, sample_format) = format.codec(depth)?;
+        if format == AudioFormat::FLAC && channels > 8 {
+            return Err(format!("FLAC supports at most 8 channels, {channels} requested").into());
+        }
+        let output = PartialFile::new(path)?;
+        let mut ctx = format::output_as(output.path(), format.extension())?;
+        let codec = encoder::find(id).ok_or(ffmpeg_next::Error::EncoderNotFound)?;
+        let layout = ChannelLayout::default(channels as i32);
+
+        let mut encoder = codec::context::Context::new()
+            .encoder()
+            .audio()?;
+        encoder.compliance(Compliance::Experimental);
+        encoder.set_rate(sample_rate as i32);
+        encoder.set_channel_layout(layout);
+        encoder.set_format(sample_format);
+        encoder.set_time_base((1, sample_rate as i32));
+        if depth == BitDepth::Int24 {
+            unsafe {
+                (*encoder.as_mut_ptr()).bits_per_raw_sample = 24;
+            }
+        }
+
+        let mut ost = ctx.add_stream(codec)?;
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+        let bits = if depth == BitDepth::Int24 { 24 } else { 0 };
+        let converter = converter(layout, sample_rate, &encoder, bits)?;
+        ctx.write_header()?;
+
+        Ok(Self {
+            ctx,
+            output,
+            encoder,
+            resampler: None,
+            converter,
+            channels,
+            input_rate: None,
+            overrun: Vec::new(),
+            timestamp: 0,
+            metadata: Default::default(),
+            cover: Default::default(),
+        })
+    }
+
+    /// Tags and cover art written in the file once flushed
+    pub fn set_tags(&mut self, metadata: HashMap<Metadata, MetadataValue>, cover: Vec<AttachedPicture>) {
+        self.metadata = metadata;
+        self.cover = cover;
+    }
+
+    pub fn cover(&self) -> &[AttachedPicture] {
+        &self.cover
+    }
+
+    pub fn set_cover(&mut self, cover: Vec<AttachedPicture>) {
+        self.cover = cover;
+    }
+
+    /// Write the buffer, resampled to the output sample rate if needed. Every
+    /// buffer must have the sample rate of the first one.
+    pub fn write(&mut self, buffer: &AudioBuffer) -> Result<(), Box<dyn std::error::Error>> {
+        let input_rate = *self.input_rate.get_or_insert(buffer.sample_rate());
+        buffer.ensure(self.channels, input_rate)?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let samples = buffer.clone().into_layout(Layout::Interleaved).into_vec();
+        let samples = if input_rate == self.encoder.rate() {
+            samples
+        } else {
+            let resampler = match self.resampler.as_mut() {
+                Some(resampler) => resampler,
+                None => self.resampler.insert(resampling::Context::get(
+                    format::Sample::F32(format::sample::Type::Packed),
+                    self.encoder.channel_layout(),
+                    input_rate,
+                    format::Sample::F32(format::sample::Type::Packed),
+                    self.encoder.channel_layout(),
+                    self.encoder.rate(),
+                )?),
+            };
+            let frames = buffer.frames() * self.encoder.rate() as usize / input_rate as usize + 256;
+            let mut resampled = Audio::new(
+                format::Sample::F32(format::sample::Type::Packed),
+                frames,
+                self.encoder.channel_layout(),
+            );
+            resampler.run(&packed_frame(&samples, self.channels, self.encoder.channel_layout()), &mut resampled)?;
+            packed_samples(resampled, self.channels)
+        };
+        self.push(samples)
+    }
+
+    /// Queue samples at the output rate and encode every complete frame
+    fn push(&mut self, samples: Vec<f32>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut samples = if self.overrun.is_empty() {
+            samples
+        } else {
+            let mut overrun = std::mem::take(&mut self.overrun);
+            overrun.extend(samples);
+            overrun
+        };
+
+        // PCM encoders take any number of samples, the others need complete frames
+        let frame_size = match self.encoder.frame_size() as usize {
+            0 => samples.len(),
+            frame_size => frame_size * self.channels,
+        };
+        if frame_size == 0 {
+            return Ok(());
+        }
+        self.overrun = samples.split_off(samples.len() - samples.len() % frame_size);
+        for chunk in samples.chunks(frame_size) {
+            self.send(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, chunk: &[f32]) -> Result<(), ffmpeg_next::Error> {
+        let mut frame = packed_frame(chunk, self.channels, self.encoder.channel_layout());
+        frame.set_rate(self.encoder.rate());
+        let mut converted = Audio::empty();
+        self.converter.run(&frame, &mut converted)?;
+        converted.set_pts(Some(self.timestamp));
+        self.timestamp += frame.samples() as i64;
+        self.encoder.send_frame(&converted)?;
+        self.receive()
+    }
+
+    fn receive(&mut self) -> Result<(), ffmpeg_next::Error> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(0);
+            encoded.rescale_ts(self.encoder.time_base(), self.ctx.stream(0).unwrap().time_base());
+            encoded.write(&mut self.ctx)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(mut resampler) = self.resampler.take() {
+            while resampler.delay().is_some() {
+                let mut resampled = Audio::new(
+                    format::Sample::F32(format::sample::Type::Packed),
+                    1024,
+                    self.encoder.channel_layout(),
+                );
+                resampler.flush(&mut resampled)?;
+                if resampled.samples() == 0 {
+                    break;
+                }
+                self.push(packed_samples(resampled, self.channels))?;
+            }
+        }
+        if !self.overrun.is_empty() {
+            let overrun = std::mem::take(&mut self.overrun);
+            self.send(&overrun)?;
+        }
+        self.encoder.send_eof()?;
+        self.receive()?;
+        self.ctx.write_trailer()?;
+        drop(self.ctx);
+
+        if !self.metadata.is_empty() || !self.cover.is_empty() {
+            let mut file = taglib::File::new(self.output.path()).map_err(|e| format!("{e:?}"))?;
+            file.set_pictures(self.cover)?;
+            write_tags(&mut file, &self.metadata)?;
+            if !file.save() {
+                return Err("unable to save file".into());
+            }
+        }
+        self.output.persist()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg_next::codec;
+
+    use crate::{
+        audiofile::{AudioFileWriter, AudioFormat, BitDepth},
+        buffer::{AudioBuffer, Layout},
+    };
+
+    #[test]
+    fn test_format_from_string() {
+        assert_eq!(AudioFormat::try_from("aiff"), Ok(AudioFormat::AIFF));
+        assert_eq!(BitDepth::try_from("24"), Ok(BitDepth::Int24));
+        assert_eq!(BitDepth::try_from("32"), Ok(BitDepth::Float32));
+        assert_eq!(BitDepth::try_from("32i"), Ok(BitDepth::Int32));
+        assert_eq!(BitDepth::Int32.to_string(), "32i");
+        assert!(AudioFormat::try_from("mp3").is_err());
+        assert!(AudioFormat::FLAC.codec(BitDepth::Float32).is_err());
+        assert!(matches!(AudioFormat::WAV.codec(BitDepth::Int32), Ok((codec::Id::PCM_S32LE, _))));
+    }
+
+    #[test]
+    fn test_can_write_flac() {
+        let mut buf = vec![0.0f32; 44100 * 5 * 2];
+        for i in 0..buf.len() / 2 {
+            buf[2 * i] = f32::sin(440.0 * i as f32 * 2.0 * std::f32::consts::PI / 44100_f32) * 0.5;
+            buf[2 * i + 1] = buf[2 * i];
+        }
+        let buf = AudioBuffer::from_vec(buf, 2, 44100, Layout::Interleaved).unwrap();
+
+        let output_filename = std::env::temp_dir().join("test_can_write_flac.flac");
+        let mut output = AudioFileWriter::new(&output_filename, AudioFormat::FLAC, BitDepth::Int24, 2, 44100).unwrap();
+        output.write(&buf).unwrap();
+        output.flush().unwrap();
+
+        let file = taglib::File::new(&output_filename).unwrap();
+        let prop = file.audioproperties().unwrap();
+        assert_eq!(prop.length(), 5);
+        assert_eq!(prop.samplerate(), 44100);
+        assert_eq!(prop.channels(), 2);
+
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+}
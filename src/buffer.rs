@@ -0,0 +1,209 @@
+/// How the samples of the different channels are arranged in an `AudioBuffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// One frame after the other, e.g. `L R L R ...`
+    #[default]
+    Interleaved,
+    /// One channel after the other, e.g. `L L ... R R ...`
+    Planar,
+}
+
+/// Floating point samples, along with their channel count, sample rate and
+/// layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioBuffer {
+    data: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    layout: Layout,
+}
+
+impl AudioBuffer {
+    /// A silent buffer of `frames` frames.
+    pub fn new(channels: usize, sample_rate: u32, frames: usize, layout: Layout) -> Self {
+        Self {
+            data: vec![0.0; channels * frames],
+            channels,
+            sample_rate,
+            layout,
+        }
+    }
+
+    pub fn from_vec(
+        data: Vec<f32>,
+        channels: usize,
+        sample_rate: u32,
+        layout: Layout,
+    ) -> Result<Self, String> {
+        if channels == 0 {
+            return Err("a buffer needs at least one channel".to_owned());
+        }
+        if !data.len().is_multiple_of(channels) {
+            return Err(format!(
+                "{} samples cannot be split in {channels} channels",
+                data.len()
+            ));
+        }
+        Ok(Self {
+            data,
+            channels,
+            sample_rate,
+            layout,
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn frames(&self) -> usize {
+        self.data.len() / self.channels
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The raw samples, arranged according to `layout`.
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    /// The samples of a single channel, only available for planar buffers.
+    pub fn plane(&self, channel: usize) -> Option<&[f32]> {
+        if self.layout != Layout::Planar || channel >= self.channels {
+            return None;
+        }
+        let frames = self.frames();
+        Some(&self.data[channel * frames..(channel + 1) * frames])
+    }
+
+    pub fn into_layout(self, layout: Layout) -> Self {
+        if self.layout == layout {
+            return self;
+        }
+        let frames = self.frames();
+        let mut data = vec![0.0; self.data.len()];
+        for frame in 0..frames {
+            for channel in 0..self.channels {
+                let (planar, interleaved) = (channel * frames + frame, frame * self.channels + channel);
+                match layout {
+                    Layout::Interleaved => data[interleaved] = self.data[planar],
+                    Layout::Planar => data[planar] = self.data[interleaved],
+                }
+            }
+        }
+        Self { data, layout, ..self }
+    }
+
+    /// Ensure the buffer has the expected channel count and sample rate.
+    pub fn ensure(&self, channels: usize, sample_rate: u32) -> Result<(), String> {
+        if self.channels != channels || self.sample_rate != sample_rate {
+            return Err(format!(
+                "expected {channels} channel(s) at {sample_rate} Hz, got {} channel(s) at {} Hz",
+                self.channels, self.sample_rate
+            ));
+        }
+        Ok(())
+    }
+
+    /// Append the frames of another buffer with the same channel count and
+    /// sample rate.
+    pub fn append(&mut self, other: &AudioBuffer) -> Result<(), String> {
+        other.ensure(self.channels, self.sample_rate)?;
+        match self.layout {
+            Layout::Interleaved => {
+                let other = other.clone().into_layout(Layout::Interleaved);
+                self.data.extend_from_slice(&other.data);
+            }
+            Layout::Planar => {
+                let this = std::mem::take(&mut self.data);
+                let other = other.clone().into_layout(Layout::Planar);
+                let (frames, other_frames) = (this.len() / self.channels, other.frames());
+                self.data = (0..self.channels)
+                    .flat_map(|channel| {
+                        this[channel * frames..(channel + 1) * frames]
+                            .iter()
+                            .chain(&other.data[channel * other_frames..(channel + 1) * other_frames])
+                            .copied()
+                    })
+                    .collect();
+            }
+        }
+        Ok(())
+    }
+
+    /// Shorten or pad the buffer with silence to `frames` frames.
+    pub fn resize(&mut self, frames: usize) {
+        match self.layout {
+            Layout::Interleaved => self.data.resize(frames * self.channels, 0.0),
+            Layout::Planar => {
+                let current = self.frames();
+                let this = std::mem::take(&mut self.data);
+                self.data = (0..self.channels)
+                    .flat_map(|channel| {
+                        this[channel * current..(channel + 1) * current]
+                            .iter()
+                            .copied()
+                            .chain(std::iter::repeat(0.0))
+                            .take(frames)
+                    })
+                    .collect();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer::{AudioBuffer, Layout};
+
+    #[test]
+    fn test_buffer_layout_conversion() {
+        let buffer = AudioBuffer::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 2, 44100, Layout::Interleaved).unwrap();
+        assert_eq!(buffer.frames(), 3);
+
+        let planar = buffer.clone().into_layout(Layout::Planar);
+        assert_eq!(planar.data(), &[1.0, 3.0, 5.0, 2.0, 4.0, 6.0]);
+        assert_eq!(planar.plane(1), Some([2.0, 4.0, 6.0].as_slice()));
+        assert_eq!(planar.into_layout(Layout::Interleaved), buffer);
+    }
+
+    #[test]
+    fn test_buffer_rejects_mismatch() {
+        assert!(AudioBuffer::from_vec(vec![0.0; 3], 2, 44100, Layout::Interleaved).is_err());
+
+        let mut buffer = AudioBuffer::new(2, 44100, 4, Layout::Interleaved);
+        assert!(buffer.append(&AudioBuffer::new(2, 48000, 4, Layout::Interleaved)).is_err());
+        assert!(buffer.append(&AudioBuffer::new(1, 44100, 4, Layout::Interleaved)).is_err());
+        assert!(buffer.append(&AudioBuffer::new(2, 44100, 4, Layout::Planar)).is_ok());
+        assert_eq!(buffer.frames(), 8);
+    }
+
+    #[test]
+    fn test_buffer_planar_resize_and_append() {
+        let mut buffer = AudioBuffer::from_vec(vec![1.0, 2.0, 3.0, 4.0], 2, 44100, Layout::Planar).unwrap();
+        buffer.resize(3);
+        assert_eq!(buffer.data(), &[1.0, 2.0, 0.0, 3.0, 4.0, 0.0]);
+        buffer.append(&AudioBuffer::from_vec(vec![5.0, 6.0], 2, 44100, Layout::Interleaved).unwrap()).unwrap();
+        assert_eq!(buffer.data(), &[1.0, 2.0, 0.0, 5.0, 3.0, 4.0, 0.0, 6.0]);
+        buffer.resize(1);
+        assert_eq!(buffer.data(), &[1.0, 3.0]);
+    }
+}
@@ -0,0 +1,315 @@
+use std::path::{Path, PathBuf};
+
+use ffmpeg_next::{
+    codec::{self, flag::Flags}, decoder, encoder, format, frame, software::scaling, Packet
+};
+use taglib::AttachedPicture;
+
+/// Images looked up next to an input without cover art, by order of preference.
+const COVER_FILES: [&str; 4] = ["cover.jpg", "cover.png", "folder.jpg", "folder.png"];
+
+/// `FF_QP2LAMBDA`, the scale of the encoder global quality
+const QP2LAMBDA: i32 = 118;
+
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    #[default]
+    JPEG,
+    PNG,
+}
+
+impl TryFrom<&str> for ImageFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "jpeg" | "jpg" => Ok(ImageFormat::JPEG),
+            "png" => Ok(ImageFormat::PNG),
+            _ => Err("unknown or unsupported image format".to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageFormat::JPEG => write!(f, "jpeg"),
+            ImageFormat::PNG => write!(f, "png"),
+        }
+    }
+}
+
+impl ImageFormat {
+    fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::JPEG => "image/jpeg",
+            ImageFormat::PNG => "image/png",
+        }
+    }
+}
+
+/// The ID3v2 picture types most relevant to a release
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
+pub enum PictureType {
+    #[default]
+    FrontCover,
+    BackCover,
+    Artist,
+    Band,
+    Illustration,
+    BandLogo,
+    Other,
+}
+
+impl TryFrom<&str> for PictureType {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "front" => Ok(PictureType::FrontCover),
+            "back" => Ok(PictureType::BackCover),
+            "artist" => Ok(PictureType::Artist),
+            "band" => Ok(PictureType::Band),
+            "illustration" => Ok(PictureType::Illustration),
+            "logo" => Ok(PictureType::BandLogo),
+            "other" => Ok(PictureType::Other),
+            _ => Err("unknown picture type".to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for PictureType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PictureType::FrontCover => write!(f, "front"),
+            PictureType::BackCover => write!(f, "back"),
+            PictureType::Artist => write!(f, "artist"),
+            PictureType::Band => write!(f, "band"),
+            PictureType::Illustration => write!(f, "illustration"),
+            PictureType::BandLogo => write!(f, "logo"),
+            PictureType::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl PictureType {
+    /// The name TagLib uses for the type
+    fn taglib_name(&self) -> &'static str {
+        match self {
+            PictureType::FrontCover => "Front Cover",
+            PictureType::BackCover => "Back Cover",
+            PictureType::Artist => "Artist",
+            PictureType::Band => "Band",
+            PictureType::Illustration => "Illustration",
+            PictureType::BandLogo => "Band Logo",
+            PictureType::Other => "Other",
+        }
+    }
+}
+
+/// How cover art gets stored. Images are only re-encoded when larger than
+/// `max_size` or not already in `format`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoverOptions {
+    /// Maximum width and height, in pixels
+    pub max_size: Option<u32>,
+    pub format: Option<ImageFormat>,
+    pub picture_type: PictureType,
+}
+
+/// Mime type of a JPEG or PNG image, from its signature.
+fn mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else {
+        None
+    }
+}
+
+/// Load a JPEG or PNG image to be used as front cover.
+pub fn from_file(path: &Path) -> Result<AttachedPicture, Box<dyn std::error::Error>> {
+    from_file_with(path, &CoverOptions::default())
+}
+
+/// Load a JPEG or PNG image, converted following `options`.
+pub fn from_file_with(path: &Path, options: &CoverOptions) -> Result<AttachedPicture, Box<dyn std::error::Error>> {
+    let data = std::fs::read(path)?;
+    let mime_type = mime_type(&data)
+        .ok_or(format!("{} is not a JPEG or PNG image", path.display()))?;
+    convert(
+        AttachedPicture {
+            mime_type: mime_type.to_owned(),
+            description: String::new(),
+            picture_type: String::new(),
+            data,
+        },
+        options,
+    )
+}
+
+/// The cover image next to `input`, such as `cover.jpg` or `folder.jpg`,
+/// regardless of the case of its name.
+pub fn find_next_to(input: &Path) -> Option<PathBuf> {
+    let dir = input.parent().map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })?;
+    let files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    COVER_FILES.iter().find_map(|name| {
+        files
+            .iter()
+            .find(|file| {
+                file.file_name()
+                    .is_some_and(|file_name| file_name.to_string_lossy().eq_ignore_ascii_case(name))
+            })
+            .cloned()
+    })
+}
+
+/// Apply `options` on a picture, resizing and re-encoding it if needed.
+pub fn convert(picture: AttachedPicture, options: &CoverOptions) -> Result<AttachedPicture, Box<dyn std::error::Error>> {
+    let picture_type = options.picture_type.taglib_name().to_owned();
+    let format = options.format.unwrap_or(match mime_type(&picture.data) {
+        Some("image/png") => ImageFormat::PNG,
+        _ => ImageFormat::JPEG,
+    });
+    let reencode = mime_type(&picture.data) != Some(format.mime_type());
+    if options.max_size.is_none() && !reencode {
+        return Ok(AttachedPicture { picture_type, ..picture });
+    }
+    let image = decode(&picture.data)?;
+    let (width, height) = fit(image.width(), image.height(), options.max_size);
+    if (width, height) == (image.width(), image.height()) && !reencode {
+        return Ok(AttachedPicture { picture_type, ..picture });
+    }
+    Ok(AttachedPicture {
+        mime_type: format.mime_type().to_owned(),
+        description: picture.description,
+        picture_type,
+        data: encode(&image, width, height, format)?,
+    })
+}
+
+/// Dimensions of an image scaled down to fit in `max_size`, keeping its aspect ratio.
+fn fit(width: u32, height: u32, max_size: Option<u32>) -> (u32, u32) {
+    match max_size {
+        Some(max_size) if width.max(height) > max_size => {
+            let scale = max_size as f64 / width.max(height) as f64;
+            (
+                ((width as f64 * scale).round() as u32).max(1),
+                ((height as f64 * scale).round() as u32).max(1),
+            )
+        }
+        _ => (width, height),
+    }
+}
+
+fn decode(data: &[u8]) -> Result<frame::Video, Box<dyn std::error::Error>> {
+    ffmpeg_next::init()?;
+    let id = match mime_type(data) {
+        Some("image/jpeg") => codec::Id::MJPEG,
+        Some("image/png") => codec::Id::PNG,
+        _ => return Err("the cover art is not a JPEG or PNG image".into()),
+    };
+    let codec = decoder::find(id).ok_or(ffmpeg_next::Error::DecoderNotFound)?;
+    let mut decoder = codec::context::Context::new_with_codec(codec).decoder().video()?;
+    decoder.send_packet(&Packet::copy(data))?;
+    decoder.send_eof()?;
+    let mut image = frame::Video::empty();
+    decoder.receive_frame(&mut image)?;
+    Ok(image)
+}
+
+fn encode(image: &frame::Video, width: u32, height: u32, image_format: ImageFormat) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (id, pixel) = match image_format {
+        ImageFormat::JPEG => (codec::Id::MJPEG, format::Pixel::YUVJ420P),
+        ImageFormat::PNG => (codec::Id::PNG, format::Pixel::RGBA),
+    };
+    let mut scaler = scaling::Context::get(
+        image.format(),
+        image.width(),
+        image.height(),
+        pixel,
+        width,
+        height,
+        scaling::Flags::BICUBIC,
+    )?;
+    let mut scaled = frame::Video::empty();
+    scaler.run(image, &mut scaled)?;
+
+    let codec = encoder::find(id).ok_or(ffmpeg_next::Error::EncoderNotFound)?;
+    let mut encoder = codec::context::Context::new_with_codec(codec).encoder().video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(pixel);
+    encoder.set_time_base((1, 1));
+    if image_format == ImageFormat::JPEG {
+        encoder.set_flags(Flags::QSCALE);
+        encoder.set_global_quality(2 * QP2LAMBDA);
+    }
+    let mut encoder = encoder.open_as(codec)?;
+    encoder.send_frame(&scaled)?;
+    encoder.send_eof()?;
+    let mut encoded = Packet::empty();
+    encoder.receive_packet(&mut encoded)?;
+    Ok(encoded.data().ok_or("unable to encode the cover art")?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cover::{convert, decode, find_next_to, fit, from_file, from_file_with, mime_type, CoverOptions, ImageFormat, PictureType};
+
+    #[test]
+    fn test_cover_mime_type() {
+        assert_eq!(mime_type(&[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+        assert_eq!(mime_type(b"GIF89a"), None);
+
+        let cover = from_file("./testdata/rocket.png".as_ref()).unwrap();
+        assert_eq!(cover.mime_type, "image/png");
+        assert!(from_file("./testdata/Oddchap - Sound 104.mp3".as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_fit_cover() {
+        assert_eq!(fit(1000, 500, Some(500)), (500, 250));
+        assert_eq!(fit(300, 600, Some(500)), (250, 500));
+        assert_eq!(fit(300, 200, Some(500)), (300, 200));
+        assert_eq!(fit(3000, 2000, None), (3000, 2000));
+    }
+
+    #[test]
+    fn test_convert_cover() {
+        let original = from_file("./testdata/rocket.png".as_ref()).unwrap();
+        assert_eq!(original.picture_type, "Front Cover");
+
+        let options = CoverOptions {
+            max_size: Some(64),
+            format: Some(ImageFormat::JPEG),
+            picture_type: PictureType::BackCover,
+        };
+        let cover = convert(original.clone(), &options).unwrap();
+        assert_eq!(cover.mime_type, "image/jpeg");
+        assert_eq!(cover.picture_type, "Back Cover");
+        assert_eq!(mime_type(&cover.data), Some("image/jpeg"));
+        let image = decode(&cover.data).unwrap();
+        assert_eq!(image.width().max(image.height()), 64);
+
+        // Nothing to convert, the image is kept as is
+        let cover = from_file_with("./testdata/rocket.png".as_ref(), &CoverOptions::default()).unwrap();
+        assert_eq!(cover.data, original.data);
+    }
+
+    #[test]
+    fn test_find_cover_next_to_input() {
+        let dir = std::env::temp_dir().join("test_find_cover_next_to_input");
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(find_next_to(&dir.join("track.mp3")), None);
+        std::fs::write(dir.join("Folder.JPG"), b"").unwrap();
+        assert_eq!(find_next_to(&dir.join("track.mp3")), Some(dir.join("Folder.JPG")));
+        std::fs::write(dir.join("cover.png"), b"").unwrap();
+        assert_eq!(find_next_to(&dir.join("track.mp3")), Some(dir.join("cover.png")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
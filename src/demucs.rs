@@ -1,4 +1,6 @@
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use ort::tensor::{Shape, TensorElementType};
 use ort::value::ValueType;
 use ndarray::{s, ArrayViewMut, ShapeBuilder};
@@ -7,11 +9,67 @@ use ort::{session::{builder::GraphOptimizationLevel, Session}, value::Tensor};
 #[cfg(feature = "cuda")]
 use ort::{execution_providers::CUDAExecutionProvider};
 
+use crate::buffer::{AudioBuffer, Layout};
 use crate::constant::DEFAULT_MODEL;
 
+/// Number of frames processed by the model at once
+pub const SEGMENT_LENGTH: usize = 343980;
+/// Sample rate expected by the model
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Instances of the model, each separating one segment at a time
+#[derive(Debug)]
+struct SessionPool {
+    idle: Mutex<Vec<Session>>,
+    released: Condvar,
+}
+
+impl SessionPool {
+    /// Take an idle session, waiting for one when they are all running
+    fn take(&self) -> Result<PooledSession<'_>, String> {
+        let mut idle = self.idle.lock().map_err(|_| "the demucs sessions are poisoned")?;
+        loop {
+            if let Some(session) = idle.pop() {
+                return Ok(PooledSession { pool: self, session: Some(session) });
+            }
+            idle = self.released.wait(idle).map_err(|_| "the demucs sessions are poisoned")?;
+        }
+    }
+}
+
+/// A session taken from the pool, given back when dropped
+struct PooledSession<'a> {
+    pool: &'a SessionPool,
+    session: Option<Session>,
+}
+
+impl Deref for PooledSession<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledSession<'_> {
+    fn deref_mut(&mut self) -> &mut Session {
+        self.session.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledSession<'_> {
+    fn drop(&mut self) {
+        if let (Some(session), Ok(mut idle)) = (self.session.take(), self.pool.idle.lock()) {
+            idle.push(session);
+            self.pool.released.notify_one();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Demucs {
-    session: Session,
+    /// Shared by the instances created with `share`
+    sessions: Arc<SessionPool>,
     input_name: String,
     output_name: String,
     input_buffer: Vec<f32>,
@@ -88,12 +146,15 @@ impl TryFrom<&str> for Model {
 
 pub struct DemusOpts {
     pub device: Device,
-    pub threads: usize
+    pub threads: usize,
+    /// Instances of the model to load, separating as many segments at once.
+    /// Each one holds its own copy of the model.
+    pub sessions: usize,
 }
 
 impl Default for DemusOpts {
     fn default() -> Self {
-        Self { threads: 2, device: Device::CPU }
+        Self { threads: 2, device: Device::CPU, sessions: 1 }
     }
 }
 
@@ -117,14 +178,16 @@ impl Demucs {
             })
             .commit()?;
 
-        let session = Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(ops.threads)?;
-
-        let session = match model {
-            Model::Local(path) => session.commit_from_file(path)?,
-            Model::Url(url) => session.commit_from_url(url)?,
+        let load = || -> Result<Session, Box<dyn std::error::Error>> {
+            let session = Session::builder()?
+                .with_optimization_level(GraphOptimizationLevel::Level3)?
+                .with_intra_threads(ops.threads)?;
+            Ok(match model {
+                Model::Local(path) => session.commit_from_file(path)?,
+                Model::Url(url) => session.commit_from_url(url)?,
+            })
         };
+        let session = load()?;
 
         if session.inputs.len() != 1 {
             return Err("expected model to have one input".into())
@@ -142,7 +205,7 @@ impl Demucs {
                     shape,
                     ..
                     // TODO support multiple buffer length and channel
-                } if *shape == Shape::new([1, 2, 343980]) => {
+                } if *shape == Shape::new([1, 2, SEGMENT_LENGTH as i64]) => {
                     Ok(input.name.to_owned())
                 }
                 _ => {
@@ -159,7 +222,7 @@ impl Demucs {
                     shape,
                     ..
                     // TODO support multiple buffer length and channel
-                } if *shape == Shape::new([1, 4, 2, 343980]) => {
+                } if *shape == Shape::new([1, 4, 2, SEGMENT_LENGTH as i64]) => {
                     Ok(output.name.to_owned())
                 }
                 _ => {
@@ -168,25 +231,45 @@ impl Demucs {
             }
         }?;
 
+        let mut sessions = vec![session];
+        for _ in 1..ops.sessions {
+            sessions.push(load()?);
+        }
         Ok(Self {
-            session,
+            sessions: Arc::new(SessionPool {
+                idle: Mutex::new(sessions),
+                released: Condvar::new(),
+            }),
             input_name,
             output_name,
-            input_buffer: Vec::with_capacity(2 * 343980),
+            input_buffer: Vec::with_capacity(2 * SEGMENT_LENGTH),
         })
 
     }
 
-    fn process(&mut self) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
-        let tensor = Tensor::<f32>::from_array(ArrayViewMut::from_shape((1, 2, 343980).strides((343980 * 2, 1, 2)), &mut self.input_buffer)?.to_owned())?;
-        let result = self.session.run(ort::inputs! {
+    /// Another instance running the same model, with its own queued samples,
+    /// so several tracks can be separated at once without loading the model
+    /// again. Their segments are processed as sessions become idle.
+    pub fn share(&self) -> Self {
+        Self {
+            sessions: self.sessions.clone(),
+            input_name: self.input_name.clone(),
+            output_name: self.output_name.clone(),
+            input_buffer: Vec::with_capacity(2 * SEGMENT_LENGTH),
+        }
+    }
+
+    fn process(&mut self) -> Result<Vec<AudioBuffer>, Box<dyn std::error::Error>> {
+        let tensor = Tensor::<f32>::from_array(ArrayViewMut::from_shape((1, 2, SEGMENT_LENGTH).strides((SEGMENT_LENGTH * 2, 1, 2)), &mut self.input_buffer[..2 * SEGMENT_LENGTH])?.to_owned())?;
+        let mut session = self.sessions.take()?;
+        let result = session.run(ort::inputs! {
             &self.input_name => tensor
         })?;
         let output = result[self.output_name.as_str()].try_extract_array::<f32>()?;
         let mut stems = vec![Vec::new(); 4];
         for (i, stem) in stems.iter_mut().enumerate() { // Iterate over the 4 items
             let mut offset = stem.len();
-            stem.resize_with(offset+2 * 343980, ||0.0f32);
+            stem.resize_with(offset+2 * SEGMENT_LENGTH, ||0.0f32);
 
             let l_slice = output.slice(s![0, i, 0, ..]); // All L values for item i
             let r_slice = output.slice(s![0, i, 1, ..]); // All R values for item i
@@ -197,37 +280,40 @@ impl Demucs {
                 offset += 2;
             }
         }
-        if self.input_buffer.len() == 2 * 343980 {
+        if self.input_buffer.len() == 2 * SEGMENT_LENGTH {
             self.input_buffer.clear();
         } else {
-            let leftover = self.input_buffer.len() - 2 * 343980;
-            let (left, right) = self.input_buffer.split_at_mut(2 * 343980);
+            let leftover = self.input_buffer.len() - 2 * SEGMENT_LENGTH;
+            let (left, right) = self.input_buffer.split_at_mut(2 * SEGMENT_LENGTH);
             left[..leftover].copy_from_slice(right);
             self.input_buffer.resize(leftover, 0.0);
         }
-        Ok(stems)
+        stems
+            .into_iter()
+            .map(|stem| Ok(AudioBuffer::from_vec(stem, 2, SAMPLE_RATE, Layout::Interleaved)?))
+            .collect()
     }
 
-    pub fn send(&mut self, sample_buffer: &[f32]) -> Result<Option<Vec<Vec<f32>>>, Box<dyn std::error::Error>> {
-        if sample_buffer.len() % 2 != 0 {
-            return Err("uneven number of sample".into());
-        }
+    /// Queue stereo samples at 44100 Hz, returning the four stems once a full
+    /// segment has been processed.
+    pub fn send(&mut self, sample_buffer: &AudioBuffer) -> Result<Option<Vec<AudioBuffer>>, Box<dyn std::error::Error>> {
+        sample_buffer.ensure(2, SAMPLE_RATE)?;
 
-        self.input_buffer.extend_from_slice(sample_buffer);
+        self.input_buffer.extend_from_slice(sample_buffer.clone().into_layout(Layout::Interleaved).data());
 
-        if self.input_buffer.len() >= 2 * 343980 {
+        if self.input_buffer.len() >= 2 * SEGMENT_LENGTH {
             Ok(Some(self.process()?))
         } else {
             Ok(None)
         }
     }
 
-    pub fn flush(&mut self) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
-        let buffer_size = self.input_buffer.len();
-        self.input_buffer.resize(2 * 343980, 0.0);
+    pub fn flush(&mut self) -> Result<Vec<AudioBuffer>, Box<dyn std::error::Error>> {
+        let frames = self.input_buffer.len() / 2;
+        self.input_buffer.resize(2 * SEGMENT_LENGTH, 0.0);
         let mut data = self.process()?;
         for stem in data.iter_mut() {
-            stem.resize(buffer_size, 0.0f32);
+            stem.resize(frames);
         }
         Ok(data)
     }
@@ -0,0 +1,222 @@
+use crate::{
+    loudness::Biquad,
+    nistem::{AtomMasteringDSP, AtomMasteringDSPCompressor, AtomMasteringDSPLimiter},
+};
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-9).log10()
+}
+
+/// Coefficient of a one pole smoothing reaching 63% of a step after `time`
+/// seconds, 0 meaning instantaneous.
+fn coefficient(time: f32, sample_rate: u32) -> f32 {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time * sample_rate as f32)).exp()
+    }
+}
+
+/// Feed forward compressor of the mastering DSP. The level is detected on the
+/// high passed signal, so that the low end triggers less gain reduction.
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    settings: AtomMasteringDSPCompressor,
+    sidechain: [Biquad; 2],
+    attack: f32,
+    release: f32,
+    /// Current gain reduction in dB
+    reduction: f32,
+}
+
+impl Compressor {
+    pub fn new(settings: &AtomMasteringDSPCompressor, sample_rate: u32) -> Self {
+        let cutoff = (settings.hp_cutoff.max(0) as f64).min(sample_rate as f64 / 2.0 - 1.0);
+        Self {
+            settings: settings.clone(),
+            sidechain: [Biquad::highpass(cutoff, sample_rate); 2],
+            attack: coefficient(settings.attack, sample_rate),
+            release: coefficient(settings.release, sample_rate),
+            reduction: 0.0,
+        }
+    }
+
+    /// Process interleaved stereo samples in place
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.settings.enabled {
+            return;
+        }
+        let input_gain = db_to_gain(self.settings.input_gain as f32);
+        let output_gain = db_to_gain(self.settings.output_gain as f32);
+        let threshold = self.settings.threshold as f32;
+        let slope = 1.0 - 1.0 / (self.settings.ratio as f32).max(1.0);
+        let wet = self.settings.dry_wet.clamp(0, 100) as f32 / 100.0;
+        for frame in samples.chunks_exact_mut(2) {
+            let mut level = 0f32;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample *= input_gain;
+                level = level.max(self.sidechain[channel].process(*sample as f64).abs() as f32);
+            }
+            let level = gain_to_db(level);
+            let target = if level > threshold { (threshold - level) * slope } else { 0.0 };
+            let coefficient = if target < self.reduction { self.attack } else { self.release };
+            self.reduction = target + coefficient * (self.reduction - target);
+            let gain = wet * db_to_gain(self.reduction) + 1.0 - wet;
+            frame.iter_mut().for_each(|sample| *sample *= gain * output_gain);
+        }
+    }
+}
+
+/// Peak limiter of the mastering DSP: the signal is raised by the threshold
+/// below 0 dB, then kept under the ceiling. The attack is instantaneous so
+/// that the ceiling is never exceeded.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    settings: AtomMasteringDSPLimiter,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(settings: &AtomMasteringDSPLimiter, sample_rate: u32) -> Self {
+        Self {
+            settings: settings.clone(),
+            release: coefficient(settings.release, sample_rate),
+            gain: 1.0,
+        }
+    }
+
+    /// Process interleaved stereo samples in place
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.settings.enabled {
+            return;
+        }
+        let drive = db_to_gain(-self.settings.threshold as f32);
+        let ceiling = db_to_gain(self.settings.ceiling as f32);
+        for frame in samples.chunks_exact_mut(2) {
+            frame.iter_mut().for_each(|sample| *sample *= drive);
+            let peak = frame.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+            let target = if peak > ceiling { ceiling / peak } else { 1.0 };
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + self.release * (self.gain - target)
+            };
+            frame.iter_mut().for_each(|sample| *sample *= self.gain);
+        }
+    }
+}
+
+/// The compressor followed by the limiter, as applied by Traktor on the sum
+/// of the stems.
+#[derive(Debug, Clone)]
+pub struct MasteringDSP {
+    compressor: Compressor,
+    limiter: Limiter,
+}
+
+impl MasteringDSP {
+    pub fn new(settings: &AtomMasteringDSP, sample_rate: u32) -> Self {
+        Self {
+            compressor: Compressor::new(&settings.compressor, sample_rate),
+            limiter: Limiter::new(&settings.limiter, sample_rate),
+        }
+    }
+
+    /// Whether any processing is applied
+    pub fn is_enabled(settings: &AtomMasteringDSP) -> bool {
+        settings.compressor.enabled || settings.limiter.enabled
+    }
+
+    /// Process interleaved stereo samples in place
+    pub fn process(&mut self, samples: &mut [f32]) {
+        self.compressor.process(samples);
+        self.limiter.process(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dsp::{Compressor, Limiter, MasteringDSP},
+        nistem::AtomMasteringDSP,
+    };
+
+    fn sine(amplitude: f32, seconds: usize) -> Vec<f32> {
+        (0..44100 * seconds)
+            .flat_map(|i| {
+                let sample = f32::sin(2.0 * std::f32::consts::PI * 440.0 * i as f32 / 44100.0) * amplitude;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn test_disabled_dsp_is_transparent() {
+        let settings = AtomMasteringDSP::default();
+        assert!(!MasteringDSP::is_enabled(&settings));
+        let mut samples = sine(0.5, 1);
+        MasteringDSP::new(&settings, 44100).process(&mut samples);
+        assert_eq!(samples, sine(0.5, 1));
+    }
+
+    #[test]
+    fn test_compressor_gain_reduction() {
+        let mut settings = AtomMasteringDSP::default().compressor;
+        settings.enabled = true;
+        settings.threshold = -20;
+        settings.ratio = 4;
+        settings.attack = 0.001;
+        settings.release = 1.0;
+        let mut samples = sine(0.5, 2);
+        Compressor::new(&settings, 44100).process(&mut samples);
+        // The -6 dB peaks are 14 dB over the threshold, reduced to 3.5 dB
+        let expected = 10f32.powf((-20.0 + 14.0 / 4.0) / 20.0);
+        let measured = peak(&samples[44100 * 2..]);
+        assert!((measured - expected).abs() < 0.01, "{measured} != {expected}");
+
+        // Below the threshold, only the gains are applied
+        settings.output_gain = 6;
+        let mut samples = sine(0.05, 1);
+        Compressor::new(&settings, 44100).process(&mut samples);
+        let expected = 0.05 * 10f32.powf(6.0 / 20.0);
+        assert!((peak(&samples) - expected).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_compressor_dry_wet() {
+        let mut settings = AtomMasteringDSP::default().compressor;
+        settings.enabled = true;
+        settings.threshold = -40;
+        settings.dry_wet = 0;
+        let mut samples = sine(0.5, 1);
+        Compressor::new(&settings, 44100).process(&mut samples);
+        assert_eq!(samples, sine(0.5, 1));
+    }
+
+    #[test]
+    fn test_limiter_ceiling() {
+        let mut settings = AtomMasteringDSP::default().limiter;
+        settings.enabled = true;
+        settings.threshold = -6;
+        settings.ceiling = -1;
+        let mut samples = sine(1.0, 2);
+        Limiter::new(&settings, 44100).process(&mut samples);
+        let ceiling = 10f32.powf(-1.0 / 20.0);
+        assert!(peak(&samples) <= ceiling + 1e-6);
+        assert!(peak(&samples[44100 * 2..]) > ceiling - 0.01);
+
+        // Quiet signals are only raised by the threshold
+        let mut samples = sine(0.1, 1);
+        Limiter::new(&settings, 44100).process(&mut samples);
+        assert!((peak(&samples) - 0.1 * 10f32.powf(6.0 / 20.0)).abs() < 0.001);
+    }
+}
@@ -0,0 +1,288 @@
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use serde_json::Value;
+use taglib::AttachedPicture;
+
+use crate::{
+    constant::{Metadata, MetadataValue},
+    nistem::{write_tags, Atom},
+    partial::PartialFile,
+    track::{read_covers, read_tags},
+};
+
+const METADATA: [Metadata; 6] = [
+    Metadata::Title,
+    Metadata::Artist,
+    Metadata::Release,
+    Metadata::Label,
+    Metadata::Genre,
+    Metadata::TrackNo,
+];
+
+/// A field modified by an `NIStemEditor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.field,
+            self.before.as_deref().unwrap_or("(none)"),
+            self.after.as_deref().unwrap_or("(none)")
+        )
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() { key.to_owned() } else { format!("{prefix}.{key}") };
+                flatten(&key, value, fields);
+            }
+        }
+        Value::Array(items) => {
+            for (i, value) in items.iter().enumerate() {
+                flatten(&format!("{prefix}[{i}]"), value, fields);
+            }
+        }
+        Value::String(value) => fields.push((prefix.to_owned(), value.to_owned())),
+        value => fields.push((prefix.to_owned(), value.to_string())),
+    }
+}
+
+fn describe_cover(cover: &[AttachedPicture]) -> Option<String> {
+    if cover.is_empty() {
+        return None;
+    }
+    Some(
+        cover
+            .iter()
+            .map(|picture| format!("{} ({} bytes)", picture.mime_type, picture.data.len()))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+/// Edit the manifest, tags and cover art of an existing stem file, leaving its
+/// audio streams untouched.
+pub struct NIStemEditor {
+    path: PathBuf,
+    manifest: Atom,
+    metadata: HashMap<Metadata, MetadataValue>,
+    cover: Option<Vec<AttachedPicture>>,
+    original_manifest: Atom,
+    original_metadata: HashMap<Metadata, MetadataValue>,
+    original_cover: Vec<AttachedPicture>,
+}
+
+impl NIStemEditor {
+    pub fn open(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = taglib::File::new(path).map_err(|e| format!("{e:?}"))?;
+        let manifest: Atom = match file.stem()? {
+            Some(manifest) => serde_json::from_str(&manifest)?,
+            None => return Err(format!("{} is not a stem file", path.display()).into()),
+        };
+        let metadata = read_tags(path);
+        let cover = read_covers(path);
+        Ok(Self {
+            path: path.clone(),
+            manifest: manifest.clone(),
+            metadata: metadata.clone(),
+            cover: None,
+            original_manifest: manifest,
+            original_metadata: metadata,
+            original_cover: cover,
+        })
+    }
+
+    pub fn manifest(&self) -> &Atom {
+        &self.manifest
+    }
+
+    pub fn manifest_mut(&mut self) -> &mut Atom {
+        &mut self.manifest
+    }
+
+    pub fn metadata(&self, key: &Metadata) -> Option<&MetadataValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn set_metadata(&mut self, key: Metadata, value: MetadataValue) {
+        self.metadata.insert(key, value);
+    }
+
+    pub fn remove_metadata(&mut self, key: &Metadata) {
+        self.metadata.remove(key);
+    }
+
+    /// Replace the cover art, an empty list removing it.
+    pub fn set_cover(&mut self, cover: Vec<AttachedPicture>) {
+        self.cover = Some(cover);
+    }
+
+    /// The fields which would be modified by `save`
+    pub fn changes(&self) -> Vec<Change> {
+        let mut changes = Vec::new();
+
+        let (mut before, mut after) = (Vec::new(), Vec::new());
+        flatten("", &serde_json::to_value(&self.original_manifest).unwrap_or_default(), &mut before);
+        flatten("", &serde_json::to_value(&self.manifest).unwrap_or_default(), &mut after);
+        for ((field, before), (_, after)) in before.into_iter().zip(after) {
+            if before != after {
+                changes.push(Change {
+                    field,
+                    before: Some(before),
+                    after: Some(after),
+                });
+            }
+        }
+
+        for key in METADATA {
+            let before = self.original_metadata.get(&key).map(MetadataValue::to_string);
+            let after = self.metadata.get(&key).map(MetadataValue::to_string);
+            if before != after {
+                changes.push(Change {
+                    field: key.to_string(),
+                    before,
+                    after,
+                });
+            }
+        }
+
+        if let Some(cover) = &self.cover {
+            let (before, after) = (describe_cover(&self.original_cover), describe_cover(cover));
+            let unchanged = cover.len() == self.original_cover.len()
+                && cover
+                    .iter()
+                    .zip(&self.original_cover)
+                    .all(|(a, b)| a.mime_type == b.mime_type && a.data == b.data);
+            if !unchanged {
+                changes.push(Change {
+                    field: "Cover".to_owned(),
+                    before,
+                    after,
+                });
+            }
+        }
+        changes
+    }
+
+    /// Write the changes to a copy of the file, which then replaces it, so
+    /// an interruption never leaves a corrupted stem file behind.
+    pub fn save(self) -> Result<(), Box<dyn std::error::Error>> {
+        self.manifest.validate()?;
+        let output = PartialFile::new(&self.path)?;
+        std::fs::copy(&self.path, output.path())
+            .map_err(|e| format!("unable to copy {}: {e}", self.path.display()))?;
+        let mut file = taglib::File::new(output.path()).map_err(|e| format!("{e:?}"))?;
+
+        file.set_stem(Some(serde_json::to_string(&self.manifest)?))?;
+
+        if let Some(cover) = self.cover {
+            file.set_pictures(cover)?;
+        }
+
+        // Clearing a field removes it from the tag
+        let mut metadata = self.metadata;
+        for key in self.original_metadata.keys() {
+            metadata.entry(*key).or_insert_with(|| match key {
+                Metadata::TrackNo => MetadataValue::Number(0),
+                _ => MetadataValue::String(String::new()),
+            });
+        }
+        write_tags(&mut file, &metadata)?;
+
+        if !file.save() {
+            return Err("unable to save file".into());
+        }
+        drop(file);
+        output.persist()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg_next::codec;
+
+    use crate::{
+        buffer::{AudioBuffer, Layout},
+        constant::{Metadata, MetadataValue},
+        editor::{Change, NIStemEditor},
+        nistem::{Atom, EncoderOptions, NIStem},
+        reader::NIStemReader,
+    };
+
+    #[test]
+    fn test_edit_stem_in_place() {
+        let buf = AudioBuffer::new(2, 44100, 44100 * 2, Layout::Interleaved);
+        let output_filename = std::env::temp_dir().join("test_edit_stem_in_place.stem.mp4");
+        if output_filename.exists() {
+            std::fs::remove_file(&output_filename).unwrap();
+        }
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::ALAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        output.clone(&"./testdata/Oddchap - Sound 104.mp3".into()).unwrap();
+        output.write_consistent(vec![buf.clone(); 5]).unwrap();
+        output.flush(Atom::default()).unwrap();
+
+        let mut editor = NIStemEditor::open(&output_filename).unwrap();
+        editor.manifest_mut().stems[0].name = "Kick".to_owned();
+        editor.manifest_mut().set_mastering_dsp("limiter.enabled", "true").unwrap();
+        editor.set_metadata(Metadata::Title, MetadataValue::String("Sound 105".to_owned()));
+        editor.remove_metadata(&Metadata::Genre);
+        assert_eq!(
+            editor.changes(),
+            vec![
+                Change {
+                    field: "mastering_dsp.limiter.enabled".to_owned(),
+                    before: Some("false".to_owned()),
+                    after: Some("true".to_owned()),
+                },
+                Change {
+                    field: "stems[0].name".to_owned(),
+                    before: Some("Drums".to_owned()),
+                    after: Some("Kick".to_owned()),
+                },
+                Change {
+                    field: "Title".to_owned(),
+                    before: Some("Sound 104".to_owned()),
+                    after: Some("Sound 105".to_owned()),
+                },
+                Change {
+                    field: "Genre".to_owned(),
+                    before: Some("Electro Swing".to_owned()),
+                    after: None,
+                },
+            ]
+        );
+        editor.save().unwrap();
+
+        let editor = NIStemEditor::open(&output_filename).unwrap();
+        assert_eq!(editor.manifest().stems[0].name, "Kick");
+        assert!(editor.manifest().mastering_dsp.limiter.enabled);
+        assert_eq!(editor.metadata(&Metadata::Title), Some(&MetadataValue::String("Sound 105".to_owned())));
+        assert_eq!(editor.metadata(&Metadata::Genre), None);
+
+        let mut reader = NIStemReader::open(&output_filename).unwrap();
+        assert_eq!(reader.read(44100 * 4).unwrap()[0].frames(), buf.frames());
+
+        // Invalid manifests are rejected, leaving the file untouched
+        let mut editor = NIStemEditor::open(&output_filename).unwrap();
+        editor.manifest_mut().stems[1].name = " ".to_owned();
+        assert!(editor.save().is_err());
+        assert_eq!(NIStemEditor::open(&output_filename).unwrap().manifest().stems[1].name, "Bass");
+
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+}
@@ -1,7 +1,17 @@
+pub mod audiofile;
+pub mod buffer;
 pub mod constant;
+pub mod cover;
 pub mod demucs;
+pub mod dsp;
+pub mod editor;
+pub mod loudness;
+pub mod multitrack;
 pub mod nistem;
+pub mod partial;
+pub mod reader;
 pub mod track;
+pub mod verify;
 
 #[cfg(test)]
 mod tests {
@@ -11,7 +21,7 @@ mod tests {
 
     use crate::{
         demucs::{Demucs, DemusOpts, Model},
-        nistem::{Atom, NIStem},
+        nistem::{Atom, EncoderOptions, NIStem},
         track::Track,
     };
 
@@ -32,14 +42,15 @@ mod tests {
         );
         let mut input = input.unwrap();
 
-        let mut buf = vec![0f32; 444866];
         let mut original_packets = vec![];
+        let mut buf;
         let data = loop {
-            match input.read(Some(&mut original_packets), &mut buf) {
-                Ok(size) => {
-                    if size != buf.len() {
+            match input.read(Some(&mut original_packets), 222433) {
+                Ok(read) => {
+                    if read.frames() != 222433 {
                         panic!("reach unexpected state")
                     }
+                    buf = read;
                 }
                 Err(err) => panic!("{}", err),
             }
@@ -55,13 +66,13 @@ mod tests {
         };
 
         let mut f = std::fs::File::create("data.pcm").unwrap();
-        for sample in buf.iter() {
+        for sample in buf.data().iter() {
             f.write_all(&f32::to_le_bytes(*sample)).unwrap();
         }
 
         for stem in &data {
-            assert_eq!(stem.len(), 444866);
-            for (idx, sample) in stem.iter().enumerate() {
+            assert_eq!(stem.frames(), 222433);
+            for (idx, sample) in stem.data().iter().enumerate() {
                 assert!(!sample.is_infinite(), "found infinite at {idx}");
                 assert!(!sample.is_nan(), "found nan at {idx}");
             }
@@ -74,6 +85,7 @@ mod tests {
             &output_filename,
             input.args(),
             (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
         );
         assert!(
             nistem.is_ok(),
@@ -0,0 +1,359 @@
+use std::f64::consts::PI;
+
+use crate::{buffer::Layout, track::Track};
+
+/// Loudness measurement following EBU R128 (ITU-R BS.1770-4, EBU Tech 3342).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// Loudness range in LU
+    pub range: f64,
+    /// True peak in dBTP
+    pub true_peak: f64,
+}
+
+/// Highest true peak a track is normalized to, in dBTP
+pub const MAX_TRUE_PEAK: f64 = -1.0;
+
+impl Loudness {
+    /// The ReplayGain 2.0 track gain, in dB, relative to the -18 LUFS reference.
+    pub fn replaygain(&self) -> f64 {
+        -18.0 - self.integrated
+    }
+
+    /// The gain, in dB, bringing the track to `target` LUFS, lowered when
+    /// needed so that its true peak stays at or below `MAX_TRUE_PEAK`.
+    pub fn normalization(&self, target: f64) -> f64 {
+        (target - self.integrated).min(MAX_TRUE_PEAK - self.true_peak)
+    }
+}
+
+impl std::fmt::Display for Loudness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1} LUFS, LRA {:.1} LU, true peak {:.1} dBTP",
+            self.integrated, self.range, self.true_peak
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    /// Second order Butterworth high pass
+    pub(crate) fn highpass(f0: f64, sample_rate: u32) -> Self {
+        let q = std::f64::consts::FRAC_1_SQRT_2;
+        let k = (PI * f0 / sample_rate as f64).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0 / a0, -2.0 / a0, 1.0 / a0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    }
+
+    pub(crate) fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    // High shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / sample_rate as f64).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    // RLB high pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / sample_rate as f64).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+    [shelf, highpass]
+}
+
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Polyphase interpolator used to estimate the inter-sample peaks.
+#[derive(Debug, Clone)]
+struct TruePeak {
+    factor: usize,
+    coefficients: Vec<f64>,
+    history: [[f64; TRUE_PEAK_TAPS]; 2],
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new(sample_rate: u32) -> Self {
+        let factor = match sample_rate {
+            0..96000 => 4,
+            96000..192000 => 2,
+            _ => 1,
+        };
+        let len = TRUE_PEAK_TAPS * factor;
+        let center = (len - 1) as f64 / 2.0;
+        let coefficients = (0..len)
+            .map(|i| {
+                let t = (i as f64 - center) / factor as f64;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                // Blackman window
+                let w = 2.0 * PI * (i as f64 + 0.5) / len as f64;
+                sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos())
+            })
+            .collect();
+        Self {
+            factor,
+            coefficients,
+            history: [[0.0; TRUE_PEAK_TAPS]; 2],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, channel: usize, x: f64) {
+        let history = &mut self.history[channel];
+        history.copy_within(..TRUE_PEAK_TAPS - 1, 1);
+        history[0] = x;
+        self.peak = self.peak.max(x.abs());
+        if self.factor == 1 {
+            return;
+        }
+        for phase in 0..self.factor {
+            let y: f64 = history
+                .iter()
+                .enumerate()
+                .map(|(k, x)| self.coefficients[k * self.factor + phase] * x)
+                .sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Streaming loudness meter for interleaved stereo samples.
+#[derive(Debug, Clone)]
+pub struct Meter {
+    filters: [[Biquad; 2]; 2],
+    true_peak: TruePeak,
+    /// Number of frames in 100ms
+    step: usize,
+    /// Frames accumulated in the current step
+    frames: usize,
+    energy: f64,
+    /// Mean square of every complete 100ms step
+    steps: Vec<f64>,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            filters: [k_weighting(sample_rate), k_weighting(sample_rate)],
+            true_peak: TruePeak::new(sample_rate),
+            step: (sample_rate as usize / 10).max(1),
+            frames: 0,
+            energy: 0.0,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(2) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                self.true_peak.process(channel, sample);
+                let [shelf, highpass] = &mut self.filters[channel];
+                let filtered = highpass.process(shelf.process(sample));
+                self.energy += filtered * filtered;
+            }
+            self.frames += 1;
+            if self.frames == self.step {
+                self.steps.push(self.energy / self.step as f64);
+                self.frames = 0;
+                self.energy = 0.0;
+            }
+        }
+    }
+
+    fn blocks(&self, len: usize) -> Vec<f64> {
+        self.steps
+            .windows(len)
+            .map(|steps| steps.iter().sum::<f64>() / len as f64)
+            .collect()
+    }
+
+    fn integrated(&self) -> f64 {
+        let blocks: Vec<f64> = self
+            .blocks(4)
+            .into_iter()
+            .filter(|energy| energy_to_loudness(*energy) > -70.0)
+            .collect();
+        if blocks.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let threshold = energy_to_loudness(blocks.iter().sum::<f64>() / blocks.len() as f64) - 10.0;
+        let gated: Vec<f64> = blocks
+            .into_iter()
+            .filter(|energy| energy_to_loudness(*energy) > threshold)
+            .collect();
+        if gated.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        energy_to_loudness(gated.iter().sum::<f64>() / gated.len() as f64)
+    }
+
+    fn range(&self) -> f64 {
+        let blocks: Vec<f64> = self
+            .blocks(30)
+            .into_iter()
+            .filter(|energy| energy_to_loudness(*energy) > -70.0)
+            .collect();
+        if blocks.is_empty() {
+            return 0.0;
+        }
+        let threshold = energy_to_loudness(blocks.iter().sum::<f64>() / blocks.len() as f64) - 20.0;
+        let mut gated: Vec<f64> = blocks
+            .into_iter()
+            .map(energy_to_loudness)
+            .filter(|loudness| *loudness > threshold)
+            .collect();
+        if gated.is_empty() {
+            return 0.0;
+        }
+        gated.sort_by(f64::total_cmp);
+        let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+        percentile(0.95) - percentile(0.10)
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: self.integrated(),
+            range: self.range(),
+            true_peak: 20.0 * self.true_peak.peak.log10(),
+        }
+    }
+}
+
+/// Measure a whole track, as it would be decoded by `Track::read`.
+pub fn analyze(track: &mut Track) -> Result<Loudness, Box<dyn std::error::Error>> {
+    let mut meter = None;
+    loop {
+        let buf = track.read(None, 102400)?;
+        meter
+            .get_or_insert_with(|| Meter::new(buf.sample_rate()))
+            .add(buf.clone().into_layout(Layout::Interleaved).data());
+        if buf.frames() != 102400 {
+            break;
+        }
+    }
+    let meter = meter.ok_or("unable to read the track")?;
+    Ok(meter.loudness())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loudness::{Meter, MAX_TRUE_PEAK};
+
+    fn sine(freq: f32, amplitude: f32, sample_rate: u32, seconds: usize) -> Vec<f32> {
+        let mut buf = vec![0.0f32; sample_rate as usize * seconds * 2];
+        for i in 0..buf.len() / 2 {
+            let value = f32::sin(2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate as f32) * amplitude;
+            buf[2 * i] = value;
+            buf[2 * i + 1] = value;
+        }
+        buf
+    }
+
+    #[test]
+    fn test_integrated_loudness_of_sine() {
+        // EBU Tech 3341: a 1kHz stereo sine at -23 dBFS reads -23 LUFS
+        let mut meter = Meter::new(48000);
+        meter.add(&sine(997.0, 10f32.powf(-23.0 / 20.0), 48000, 20));
+        let loudness = meter.loudness();
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "got {loudness:?}");
+        assert!(loudness.range < 0.1, "got {loudness:?}");
+    }
+
+    #[test]
+    fn test_integrated_loudness_is_gated() {
+        let mut meter = Meter::new(44100);
+        meter.add(&sine(997.0, 10f32.powf(-20.0 / 20.0), 44100, 10));
+        meter.add(&vec![0.0; 44100 * 2 * 10]);
+        let loudness = meter.loudness();
+        assert!((loudness.integrated + 20.0).abs() < 0.1, "got {loudness:?}");
+    }
+
+    #[test]
+    fn test_loudness_range() {
+        let mut meter = Meter::new(44100);
+        meter.add(&sine(997.0, 10f32.powf(-30.0 / 20.0), 44100, 20));
+        meter.add(&sine(997.0, 10f32.powf(-20.0 / 20.0), 44100, 20));
+        let loudness = meter.loudness();
+        assert!((loudness.range - 10.0).abs() < 0.5, "got {loudness:?}");
+    }
+
+    #[test]
+    fn test_true_peak() {
+        // A sine at a quarter of the sample rate, sampled 45 degrees off its
+        // peaks, has its true peak 3 dB above its sample peak.
+        let mut buf = vec![0.0f32; 44100 * 2];
+        for i in 0..buf.len() / 2 {
+            let value = f32::sin(std::f32::consts::PI * (i as f32 / 2.0 + 0.25)) * 0.5;
+            buf[2 * i] = value;
+            buf[2 * i + 1] = value;
+        }
+        let mut meter = Meter::new(44100);
+        meter.add(&buf);
+        let loudness = meter.loudness();
+        assert!((loudness.true_peak + 6.02).abs() < 0.2, "got {loudness:?}");
+    }
+
+    #[test]
+    fn test_normalization_keeps_true_peak() {
+        let mut meter = Meter::new(44100);
+        meter.add(&sine(997.0, 10f32.powf(-23.0 / 20.0), 44100, 10));
+        let loudness = meter.loudness();
+        assert!((loudness.normalization(-16.0) - 7.0).abs() < 0.1, "got {loudness:?}");
+
+        // A quiet track with sharp transients reaches its true peak limit
+        // long before the target loudness
+        let mut buf = sine(997.0, 10f32.powf(-40.0 / 20.0), 44100, 10);
+        for frame in buf.chunks_mut(2).step_by(4410) {
+            frame.fill(0.7);
+        }
+        let mut meter = Meter::new(44100);
+        meter.add(&buf);
+        let loudness = meter.loudness();
+        let gain = loudness.normalization(-14.0);
+        assert!(gain < -14.0 - loudness.integrated, "got {loudness:?}");
+        assert!((loudness.true_peak + gain - MAX_TRUE_PEAK).abs() < 1e-9, "got {loudness:?}");
+    }
+}
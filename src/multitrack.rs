@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+use taglib::AttachedPicture;
+
+use crate::{
+    audiofile::{AudioFileWriter, AudioFormat, BitDepth},
+    buffer::{AudioBuffer, Layout},
+    track::{read_covers, read_tags},
+};
+
+/// Where to write the master and the stems.
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A NI stem MP4
+    #[default]
+    Stem,
+    /// One stereo file per stream
+    Files(AudioFormat),
+    /// A single file with 10 channels, the master followed by each stem
+    Multichannel(AudioFormat),
+}
+
+impl TryFrom<&str> for OutputFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.strip_prefix("multichannel-") {
+            _ if value == "stem" => Ok(OutputFormat::Stem),
+            // FLAC streams are limited to 8 channels
+            Some("flac") => Err(
+                "FLAC cannot hold the 10 channels of a multichannel file, use multichannel-wav or multichannel-aiff"
+                    .to_owned(),
+            ),
+            Some(format) => Ok(OutputFormat::Multichannel(format.try_into()?)),
+            None => Ok(OutputFormat::Files(value.try_into()?)),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Stem => write!(f, "stem"),
+            OutputFormat::Files(format) => write!(f, "{format}"),
+            OutputFormat::Multichannel(format) => write!(f, "multichannel-{format}"),
+        }
+    }
+}
+
+/// Plain audio files alternative to `NIStem`, taking the master and the four
+/// stems the same way as `NIStem::write_consistent`.
+pub enum Multitrack {
+    Files(Vec<AudioFileWriter>),
+    Multichannel(AudioFileWriter),
+}
+
+impl Multitrack {
+    /// One file for the master, then one for each stem
+    pub fn new_with_files(
+        paths: &[PathBuf],
+        format: AudioFormat,
+        depth: BitDepth,
+        sample_rate: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if paths.len() != 5 {
+            return Err("expected a path for the master and each stem".into());
+        }
+        Ok(Self::Files(
+            paths
+                .iter()
+                .map(|path| AudioFileWriter::new(path, format, depth, 2, sample_rate))
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    pub fn new_multichannel(
+        path: &PathBuf,
+        format: AudioFormat,
+        depth: BitDepth,
+        sample_rate: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::Multichannel(AudioFileWriter::new(path, format, depth, 10, sample_rate)?))
+    }
+
+    /// Copy the tags and cover art of `path` in every file
+    pub fn clone(&mut self, path: &PathBuf) {
+        let (metadata, cover) = (read_tags(path), read_covers(path));
+        match self {
+            Multitrack::Files(writers) => writers
+                .iter_mut()
+                .for_each(|writer| writer.set_tags(metadata.clone(), cover.clone())),
+            Multitrack::Multichannel(writer) => writer.set_tags(metadata, cover),
+        }
+    }
+
+    pub fn cover(&self) -> &[AttachedPicture] {
+        match self {
+            Multitrack::Files(writers) => writers.first().map(AudioFileWriter::cover).unwrap_or_default(),
+            Multitrack::Multichannel(writer) => writer.cover(),
+        }
+    }
+
+    /// Cover art written in every file
+    pub fn set_cover(&mut self, cover: Vec<AttachedPicture>) {
+        match self {
+            Multitrack::Files(writers) => writers.iter_mut().for_each(|writer| writer.set_cover(cover.clone())),
+            Multitrack::Multichannel(writer) => writer.set_cover(cover),
+        }
+    }
+
+    pub fn write(&mut self, stems: Vec<AudioBuffer>) -> Result<(), Box<dyn std::error::Error>> {
+        if stems.len() != 5 {
+            return Err("unexpected buffer count".into());
+        }
+        match self {
+            Multitrack::Files(writers) => {
+                for (writer, stem) in writers.iter_mut().zip(&stems) {
+                    writer.write(stem)?;
+                }
+            }
+            Multitrack::Multichannel(writer) => writer.write(&interleave(stems)?)?,
+        }
+        Ok(())
+    }
+
+    pub fn flush(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Multitrack::Files(writers) => writers.into_iter().try_for_each(AudioFileWriter::flush),
+            Multitrack::Multichannel(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Merge stereo buffers into a single one with all their channels, the
+/// shorter ones being padded with silence.
+fn interleave(stems: Vec<AudioBuffer>) -> Result<AudioBuffer, String> {
+    let sample_rate = stems[0].sample_rate();
+    let frames = stems.iter().map(AudioBuffer::frames).max().unwrap_or_default();
+    let mut data = Vec::with_capacity(frames * 2 * stems.len());
+    for mut stem in stems.into_iter().map(|stem| stem.into_layout(Layout::Planar)) {
+        stem.ensure(2, sample_rate)?;
+        stem.resize(frames);
+        data.extend_from_slice(stem.data());
+    }
+    Ok(AudioBuffer::from_vec(data, 10, sample_rate, Layout::Planar)?.into_layout(Layout::Interleaved))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audiofile::AudioFormat,
+        buffer::{AudioBuffer, Layout},
+        multitrack::{interleave, OutputFormat},
+    };
+
+    #[test]
+    fn test_output_format_from_string() {
+        assert_eq!(OutputFormat::try_from("stem"), Ok(OutputFormat::Stem));
+        assert_eq!(OutputFormat::try_from("flac"), Ok(OutputFormat::Files(AudioFormat::FLAC)));
+        assert_eq!(OutputFormat::try_from("multichannel-wav"), Ok(OutputFormat::Multichannel(AudioFormat::WAV)));
+        assert_eq!(OutputFormat::Multichannel(AudioFormat::WAV).to_string(), "multichannel-wav");
+        assert!(OutputFormat::try_from("multichannel-stem").is_err());
+        assert!(OutputFormat::try_from("multichannel-flac").is_err());
+    }
+
+    #[test]
+    fn test_interleave_stems() {
+        let stems = (0..5)
+            .map(|i| {
+                let frames = if i == 4 { 1 } else { 2 };
+                AudioBuffer::from_vec(vec![i as f32; frames * 2], 2, 44100, Layout::Interleaved).unwrap()
+            })
+            .collect();
+        let buffer = interleave(stems).unwrap();
+        assert_eq!(buffer.channels(), 10);
+        assert_eq!(
+            buffer.data(),
+            &[
+                0.0, 0.0, 1.0, 1.0, 2.0, 2.0, 3.0, 3.0, 4.0, 4.0,
+                0.0, 0.0, 1.0, 1.0, 2.0, 2.0, 3.0, 3.0, 0.0, 0.0,
+            ]
+        );
+    }
+}
@@ -1,7 +1,7 @@
-use std::{collections::HashMap, fmt, path::PathBuf};
+use std::{collections::HashMap, ffi::CString, fmt, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::mpsc};
 
 use ffmpeg_next::{
-    codec::{self, Compliance}, encoder::{self}, ffi::AVFMT_FLAG_GENPTS, format::{self, context}, frame::Audio, software::resampling, ChannelLayout, Packet, Rational
+    codec::{self, Capabilities, Compliance}, encoder::{self}, ffi::{av_dict_set, AVFMT_FLAG_GENPTS}, format::{self, context, stream::Disposition}, frame::Audio, software::resampling, ChannelLayout, Dictionary, Packet, Rational, Rescale
 };
 use serde::{
     Deserialize, Deserializer, Serialize,
@@ -9,7 +9,7 @@ use serde::{
 };
 use taglib::AttachedPicture;
 
-use crate::constant::{Metadata, MetadataValue, STEM_DEFAULT_COLOR, STEM_DEFAULT_LABEL};
+use crate::{audiofile::{packed_frame, packed_samples, BitDepth}, buffer::{AudioBuffer, Layout}, dsp::MasteringDSP, constant::{Metadata, MetadataValue, STEM_DEFAULT_COLOR, STEM_DEFAULT_LABEL}, loudness::{Loudness, Meter}, partial::PartialFile, track::Gapless};
 
 #[derive(Debug, Clone, Default, Copy)]
 pub enum Codec {
@@ -20,11 +20,17 @@ pub enum Codec {
     OPUS,
 }
 
-#[derive(Debug, Clone, Default, Copy)]
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
 pub enum SampleRate {
     #[default]
     Hz44100,
     Hz48000,
+    Hz88200,
+    Hz96000,
+    Hz176400,
+    Hz192000,
+    /// Any other rate, checked against the codec when creating the streams
+    Other(u32),
 }
 
 impl TryFrom<&str> for Codec {
@@ -67,41 +73,296 @@ impl TryFrom<&str> for SampleRate {
     type Error = String;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
-            "48000" => Ok(SampleRate::Hz48000),
-            "44100" => Ok(SampleRate::Hz44100),
-            _ => Err("unsupported samplerate".to_owned()),
+        match value.parse::<u32>() {
+            Ok(rate @ 8000..=384000) => Ok(rate.into()),
+            _ => Err("unsupported samplerate, expected a rate between 8000 and 384000".to_owned()),
         }
     }
 }
 
-impl From<SampleRate> for i32 {
-    fn from(val: SampleRate) -> Self {
+impl From<u32> for SampleRate {
+    fn from(val: u32) -> Self {
         match val {
-            SampleRate::Hz48000 => 48000,
-            SampleRate::Hz44100 => 44100,
+            44100 => SampleRate::Hz44100,
+            48000 => SampleRate::Hz48000,
+            88200 => SampleRate::Hz88200,
+            96000 => SampleRate::Hz96000,
+            176400 => SampleRate::Hz176400,
+            192000 => SampleRate::Hz192000,
+            rate => SampleRate::Other(rate),
         }
     }
 }
 
-impl From<SampleRate> for u64 {
+impl From<SampleRate> for u32 {
     fn from(val: SampleRate) -> Self {
         match val {
-            SampleRate::Hz48000 => 48000,
             SampleRate::Hz44100 => 44100,
+            SampleRate::Hz48000 => 48000,
+            SampleRate::Hz88200 => 88200,
+            SampleRate::Hz96000 => 96000,
+            SampleRate::Hz176400 => 176400,
+            SampleRate::Hz192000 => 192000,
+            SampleRate::Other(rate) => rate,
         }
     }
 }
 
+impl From<SampleRate> for i32 {
+    fn from(val: SampleRate) -> Self {
+        u32::from(val) as i32
+    }
+}
+
+impl From<SampleRate> for u64 {
+    fn from(val: SampleRate) -> Self {
+        u32::from(val).into()
+    }
+}
+
 impl std::fmt::Display for SampleRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} Hz", u32::from(*self))
+    }
+}
+
+impl SampleRate {
+    /// The closest rate supported by `codec`, preferring the higher ones
+    pub fn supported_by(self, codec: codec::Id) -> Result<SampleRate, String> {
+        let rates = supported_rates(codec)?;
+        let rate = u32::from(self) as i32;
+        if rates.is_empty() || rates.contains(&rate) {
+            return Ok(self);
+        }
+        let closest = rates
+            .iter()
+            .filter(|r| **r > rate)
+            .min()
+            .or_else(|| rates.iter().max())
+            .copied()
+            .unwrap_or(rate);
+        Ok((closest as u32).into())
+    }
+}
+
+/// The sample rates supported by the encoder of `codec`, empty when any rate
+/// may be used.
+fn supported_rates(codec: codec::Id) -> Result<Vec<i32>, String> {
+    let encoder = encoder::find(codec).ok_or(format!("no encoder available for {codec:?}"))?;
+    Ok(encoder
+        .audio()
+        .map_err(|e| e.to_string())?
+        .rates()
+        .map(|rates| rates.collect())
+        .unwrap_or_default())
+}
+
+/// Ensure `codec` can encode at `sample_rate`
+pub fn check_sample_rate(codec: codec::Id, sample_rate: i32) -> Result<(), String> {
+    let rates = supported_rates(codec)?;
+    if rates.is_empty() || rates.contains(&sample_rate) {
+        return Ok(());
+    }
+    Err(format!(
+        "{sample_rate} Hz isn't supported by {codec:?}, supported rates are {}",
+        rates.iter().map(i32::to_string).collect::<Vec<_>>().join(", ")
+    ))
+}
+
+/// Properties describing how the source was encoded, which don't apply to
+/// the stem file
+const SOURCE_ONLY_PROPERTIES: [&str; 2] = ["ITUNSMPB", "ITUNNORM"];
+
+/// Complex properties handled on their own
+const HANDLED_COMPLEX_PROPERTIES: [&str; 2] = ["PICTURE", "STEM"];
+
+/// Codecs of an original master which the MP4 muxer can store as is and
+/// Traktor can play. Others, such as Vorbis, WMA, APE or PCM, need to be
+/// re-encoded with the stem codec.
+const PRESERVABLE_CODECS: [codec::Id; 5] = [
+    codec::Id::AAC,
+    codec::Id::MP3,
+    codec::Id::ALAC,
+    codec::Id::FLAC,
+    codec::Id::OPUS,
+];
+
+/// Ensure an original master encoded with `codec` can be preserved in a NI
+/// stem file
+pub fn check_preservable(codec: codec::Id) -> Result<(), String> {
+    if PRESERVABLE_CODECS.contains(&codec) {
+        Ok(())
+    } else {
+        Err(format!("{codec:?} can't be stored as is in a NI stem file"))
+    }
+}
+
+/// Check that a language is an ISO 639-2 code, as stored by MP4
+pub fn check_language(language: &str) -> Result<(), String> {
+    if language.len() == 3 && language.bytes().all(|c| c.is_ascii_lowercase()) {
+        Ok(())
+    } else {
+        Err(format!("{language} is not an ISO 639-2 language code, such as eng"))
+    }
+}
+
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
+pub enum OpusApplication {
+    Voip,
+    #[default]
+    Audio,
+    LowDelay,
+}
+
+impl TryFrom<&str> for OpusApplication {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "voip" => Ok(OpusApplication::Voip),
+            "audio" => Ok(OpusApplication::Audio),
+            "lowdelay" => Ok(OpusApplication::LowDelay),
+            _ => Err("unknown Opus application".to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for OpusApplication {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SampleRate::Hz48000 => write!(f, "48000 Hz"),
-            SampleRate::Hz44100 => write!(f, "44100 Hz"),
+            OpusApplication::Voip => write!(f, "voip"),
+            OpusApplication::Audio => write!(f, "audio"),
+            OpusApplication::LowDelay => write!(f, "lowdelay"),
         }
     }
 }
 
+/// `FF_QP2LAMBDA`, the scale of the encoder global quality
+const QP2LAMBDA: f32 = 118.0;
+
+/// Settings of the stem encoders. Unset values are left to the FFmpeg
+/// defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncoderOptions {
+    /// Target bitrate in bits per second, for AAC and Opus
+    pub bitrate: Option<usize>,
+    /// AAC variable bitrate quality, from 0.1 to 2
+    pub quality: Option<f32>,
+    /// Opus variable bitrate, enabled by default
+    pub vbr: Option<bool>,
+    /// FLAC (0 to 12), ALAC (0 to 2) or Opus (0 to 10) compression level
+    pub compression_level: Option<usize>,
+    pub application: Option<OpusApplication>,
+    /// Sample depth of the ALAC or FLAC streams, the first format supported
+    /// by the encoder being used otherwise
+    pub bit_depth: Option<BitDepth>,
+}
+
+impl EncoderOptions {
+    pub fn validate(&self, codec: codec::Id) -> Result<(), String> {
+        let name = match codec {
+            codec::Id::AAC => "AAC",
+            codec::Id::OPUS => "Opus",
+            codec::Id::FLAC => "FLAC",
+            codec::Id::ALAC => "ALAC",
+            _ => "this codec",
+        };
+        if let Some(bitrate) = self.bitrate {
+            let range = match codec {
+                codec::Id::AAC => 16_000..=512_000,
+                codec::Id::OPUS => 6_000..=510_000,
+                _ => return Err(format!("a bitrate cannot be used with {name}")),
+            };
+            if !range.contains(&bitrate) {
+                return Err(format!(
+                    "the {name} bitrate must be between {} and {} bits per second",
+                    range.start(),
+                    range.end()
+                ));
+            }
+        }
+        if let Some(quality) = self.quality {
+            if codec != codec::Id::AAC {
+                return Err(format!("a quality cannot be used with {name}"));
+            }
+            if self.bitrate.is_some() {
+                return Err("a quality and a bitrate cannot be used together".to_owned());
+            }
+            if !(0.1..=2.0).contains(&quality) {
+                return Err("the AAC quality must be between 0.1 and 2".to_owned());
+            }
+        }
+        if self.vbr.is_some() && codec != codec::Id::OPUS {
+            return Err(format!("the variable bitrate cannot be toggled with {name}"));
+        }
+        if let Some(level) = self.compression_level {
+            let max = match codec {
+                codec::Id::FLAC => 12,
+                codec::Id::ALAC => 2,
+                codec::Id::OPUS => 10,
+                _ => return Err(format!("a compression level cannot be used with {name}")),
+            };
+            if level > max {
+                return Err(format!("the {name} compression level must be between 0 and {max}"));
+            }
+        }
+        if self.application.is_some() && codec != codec::Id::OPUS {
+            return Err(format!("an application mode cannot be used with {name}"));
+        }
+        match (self.bit_depth, codec) {
+            (Some(BitDepth::Float32), codec::Id::FLAC) => return Err("FLAC doesn't support floating point samples".to_owned()),
+            (None, _) | (Some(_), codec::Id::FLAC) => {}
+            (Some(BitDepth::Int32 | BitDepth::Float32), codec::Id::ALAC) => return Err("ALAC supports at most 24 bits".to_owned()),
+            (Some(_), codec::Id::ALAC) => {}
+            (Some(_), _) => return Err(format!("a bit depth cannot be used with {name}")),
+        }
+        Ok(())
+    }
+
+    /// The sample format to encode with, along with the number of bits
+    /// actually used (0 for the encoder default). FLAC keeps only 24 of 32
+    /// bits integer samples unless told otherwise.
+    fn sample_format(&self, codec: ffmpeg_next::Codec) -> Result<(format::Sample, usize), ffmpeg_next::Error> {
+        let mut formats = codec
+            .audio()?
+            .formats()
+            .ok_or(ffmpeg_next::Error::InvalidData)?;
+        let Some(depth) = self.bit_depth else {
+            return Ok((formats.next().ok_or(ffmpeg_next::Error::InvalidData)?, 0));
+        };
+        let formats: Vec<_> = formats.collect();
+        let find = |wanted: fn(&format::Sample) -> bool| formats.iter().copied().find(wanted);
+        let selected = match depth {
+            BitDepth::Int16 => find(|f| matches!(f, format::Sample::I16(_))).map(|f| (f, 0)),
+            BitDepth::Int24 => find(|f| matches!(f, format::Sample::I32(_))).map(|f| (f, 24)),
+            BitDepth::Int32 => find(|f| matches!(f, format::Sample::I32(_))).map(|f| (f, 32)),
+            BitDepth::Float32 => find(|f| matches!(f, format::Sample::F32(_))).map(|f| (f, 0)),
+        };
+        selected.ok_or(ffmpeg_next::Error::InvalidData)
+    }
+
+    /// Apply the options on an encoder about to be opened, returning the
+    /// codec private options.
+    fn apply(&self, encoder: &mut encoder::audio::Audio) -> Dictionary<'static> {
+        if let Some(bitrate) = self.bitrate {
+            encoder.set_bit_rate(bitrate);
+        }
+        if let Some(quality) = self.quality {
+            encoder.set_flags(encoder.flags() | codec::flag::Flags::QSCALE);
+            encoder.set_quality((quality * QP2LAMBDA).round() as usize);
+        }
+        encoder.set_compression(self.compression_level);
+        let mut options = Dictionary::new();
+        if let Some(vbr) = self.vbr {
+            options.set("vbr", if vbr { "on" } else { "off" });
+        }
+        if let Some(application) = self.application {
+            options.set("application", &application.to_string());
+        }
+        options
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub struct Color(pub i32);
 
@@ -258,33 +519,449 @@ impl Default for Atom {
     }
 }
 
+impl Atom {
+    /// Set a mastering DSP parameter from its dotted path, e.g.
+    /// `limiter.enabled` or `compressor.ratio`, and its value as JSON.
+    pub fn set_mastering_dsp(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let mut dsp = serde_json::to_value(&self.mastering_dsp).map_err(|e| e.to_string())?;
+        let field = key
+            .split('.')
+            .try_fold(&mut dsp, |value, key| value.get_mut(key))
+            .filter(|value| !value.is_object())
+            .ok_or(format!("unknown mastering DSP parameter {key}"))?;
+        *field = serde_json::from_str(value).map_err(|_| format!("invalid value for {key}: {value}"))?;
+        self.mastering_dsp = serde_json::from_value(dsp).map_err(|_| format!("invalid value for {key}: {value}"))?;
+        Ok(())
+    }
+
+    /// Load a manifest from a JSON file, such as the one printed by
+    /// `stemgen manifest`, and validate it.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = std::fs::File::open(path).map_err(|e| format!("unable to open {}: {e}", path.display()))?;
+        let manifest: Atom = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| format!("invalid manifest {}: {e}", path.display()))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// The manifest as indented JSON, which `from_file` can load back
+    pub fn to_json(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Ensure the manifest is one Traktor can use: a supported version,
+    /// labels for every stem and mastering DSP parameters within their range.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version != 1 {
+            return Err(format!("unsupported manifest version {}, only 1 is supported", self.version));
+        }
+        for (idx, stem) in self.stems.iter().enumerate() {
+            if stem.name.trim().is_empty() {
+                return Err(format!("stems[{idx}].name must not be empty"));
+            }
+        }
+        fn check<T: PartialOrd + fmt::Display>(key: &str, value: T, min: T, max: T) -> Result<(), String> {
+            if value < min || value > max {
+                return Err(format!("mastering_dsp.{key} must be between {min} and {max}, got {value}"));
+            }
+            Ok(())
+        }
+        let compressor = &self.mastering_dsp.compressor;
+        check("compressor.ratio", compressor.ratio, 1, 100)?;
+        check("compressor.input_gain", compressor.input_gain, -24, 24)?;
+        check("compressor.output_gain", compressor.output_gain, -24, 24)?;
+        check("compressor.attack", compressor.attack, 0.0001, 0.1)?;
+        check("compressor.release", compressor.release, 0.01, 10.0)?;
+        check("compressor.threshold", compressor.threshold, -80, 0)?;
+        check("compressor.hp_cutoff", compressor.hp_cutoff, 20, 500)?;
+        check("compressor.dry_wet", compressor.dry_wet, 0, 100)?;
+        let limiter = &self.mastering_dsp.limiter;
+        check("limiter.release", limiter.release, 0.01, 10.0)?;
+        check("limiter.threshold", limiter.threshold, -24, 0)?;
+        check("limiter.ceiling", limiter.ceiling, -24, 0)?;
+        Ok(())
+    }
+}
+
+/// Errors of the stream encoders, which run on their own thread
+type EncodeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An encoded stream of the stem file, fed with stereo interleaved samples
+struct StemStream {
+    index: usize,
+    encoder: encoder::Audio,
+    /// Converts to the encoder sample rate, set up with the rate of the first buffers
+    resampler: Option<resampling::Context>,
+    /// Converts to the sample format of the encoder
+    converter: resampling::Context,
+    /// Samples at the encoder rate, not yet making a complete frame
+    overrun: Vec<f32>,
+    /// Frames sent to the encoder so far, in its time base
+    timestamp: i64,
+    /// Packets received from the encoder so far, including the ones it
+    /// emits when flushed
+    encoded: i64,
+}
+
+impl StemStream {
+    fn capabilities(&self) -> Capabilities {
+        self.encoder
+            .codec()
+            .map(|codec| codec.capabilities())
+            .unwrap_or(Capabilities::empty())
+    }
+
+    /// Interleaved samples per frame the encoder expects, or `None` when it
+    /// takes any number of them
+    fn frame_size(&self) -> Option<usize> {
+        match self.encoder.frame_size() as usize {
+            0 => None,
+            _ if self.capabilities().contains(Capabilities::VARIABLE_FRAME_SIZE) => None,
+            frame_size => Some(2 * frame_size),
+        }
+    }
+
+    /// Resample samples at `input_rate` to the encoder rate, and encode every
+    /// complete frame. The packets are left to the caller to mux, in the
+    /// encoder time base.
+    fn write(&mut self, samples: Vec<f32>, input_rate: u32) -> Result<Vec<Packet>, EncodeError> {
+        let mut packets = Vec::new();
+        if samples.is_empty() {
+            return Ok(packets);
+        }
+        if input_rate == self.encoder.rate() {
+            self.overrun.extend(samples);
+        } else {
+            let resampler = match self.resampler.as_mut() {
+                Some(resampler) => resampler,
+                None => self.resampler.insert(resampling::Context::get(
+                    format::Sample::F32(format::sample::Type::Packed),
+                    ChannelLayout::STEREO,
+                    input_rate,
+                    format::Sample::F32(format::sample::Type::Packed),
+                    ChannelLayout::STEREO,
+                    self.encoder.rate(),
+                )?),
+            };
+            let frames = samples.len() / 2 * self.encoder.rate() as usize / input_rate as usize + 256;
+            let mut resampled = Audio::new(format::Sample::F32(format::sample::Type::Packed), frames, ChannelLayout::STEREO);
+            resampler.run(&packed_frame(&samples, 2, ChannelLayout::STEREO), &mut resampled)?;
+            self.overrun.extend(packed_samples(resampled, 2));
+        }
+
+        let len = match self.frame_size() {
+            Some(frame_size) => self.overrun.len() - self.overrun.len() % frame_size,
+            None => self.overrun.len(),
+        };
+        let samples: Vec<f32> = self.overrun.drain(..len).collect();
+        for chunk in samples.chunks(self.frame_size().unwrap_or(len.max(1))) {
+            self.send(&mut packets, chunk)?;
+        }
+        Ok(packets)
+    }
+
+    /// Encode the remaining samples. The last frame is only padded with
+    /// silence when the encoder can't take a smaller one.
+    fn flush(&mut self) -> Result<Vec<Packet>, EncodeError> {
+        let mut packets = Vec::new();
+        if let Some(mut resampler) = self.resampler.take() {
+            while resampler.delay().is_some() {
+                let mut resampled = Audio::new(format::Sample::F32(format::sample::Type::Packed), 1024, ChannelLayout::STEREO);
+                resampler.flush(&mut resampled)?;
+                if resampled.samples() == 0 {
+                    break;
+                }
+                self.overrun.extend(packed_samples(resampled, 2));
+            }
+        }
+        let mut samples = std::mem::take(&mut self.overrun);
+        let frame_size = self.frame_size().unwrap_or(samples.len().max(1));
+        if samples.len() % frame_size != 0 && !self.capabilities().contains(Capabilities::SMALL_LAST_FRAME) {
+            samples.resize(samples.len() + frame_size - samples.len() % frame_size, 0.0);
+        }
+        for chunk in samples.chunks(frame_size) {
+            self.send(&mut packets, chunk)?;
+        }
+        self.encoder.send_eof()?;
+        self.receive(&mut packets);
+        Ok(packets)
+    }
+
+    /// The iTunSMPB gapless metadata of the stream: the encoder delay, the
+    /// padding up to the end of the last packet and the original length. The
+    /// padding counts every packet, including the ones emitted when flushing.
+    fn itunsmpb(&self) -> String {
+        let delay = unsafe { (*self.encoder.as_ptr()).initial_padding.max(0) } as i64;
+        let frame_size = (self.encoder.frame_size() as i64).max(1);
+        let padding = (self.encoded * frame_size - delay - self.timestamp).max(0);
+        format!(
+            " 00000000 {delay:08X} {padding:08X} {:016X}{}",
+            self.timestamp,
+            " 00000000".repeat(8)
+        )
+    }
+
+    fn run(&mut self, job: Job) -> Result<Vec<Packet>, EncodeError> {
+        match job {
+            Job::Write(samples, input_rate) => self.write(samples, input_rate),
+            Job::Flush => self.flush(),
+        }
+    }
+
+    fn send(&mut self, packets: &mut Vec<Packet>, chunk: &[f32]) -> Result<(), ffmpeg_next::Error> {
+        let mut frame = packed_frame(chunk, 2, ChannelLayout::STEREO);
+        frame.set_rate(self.encoder.rate());
+        let mut converted = Audio::empty();
+        self.converter.run(&frame, &mut converted)?;
+        converted.set_pts(Some(self.timestamp));
+        self.timestamp += frame.samples() as i64;
+        self.encoder.send_frame(&converted)?;
+        self.receive(packets);
+        Ok(())
+    }
+
+    fn receive(&mut self, packets: &mut Vec<Packet>) {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            if !unsafe { encoded.is_empty() } {
+                self.encoded += 1;
+                packets.push(std::mem::replace(&mut encoded, Packet::empty()));
+            }
+        }
+    }
+}
+
+/// Work for the encoder of a stream
+enum Job {
+    /// Stereo interleaved samples, at the given sample rate
+    Write(Vec<f32>, u32),
+    Flush,
+}
+
+/// A stream encoded on a thread of its own, kept for the lifetime of the
+/// `NIStem` and fed through a channel
+struct Worker {
+    index: usize,
+    time_base: Rational,
+    jobs: mpsc::Sender<Job>,
+    packets: mpsc::Receiver<Result<Vec<Packet>, EncodeError>>,
+    thread: std::thread::JoinHandle<StemStream>,
+}
+
+impl Worker {
+    fn spawn(mut stream: StemStream) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let (results, packets) = mpsc::channel();
+        let (index, time_base) = (stream.index, stream.encoder.time_base());
+        // Stops once the `NIStem` drops its end of the channel
+        let thread = std::thread::spawn(move || {
+            for job in queue {
+                if results.send(stream.run(job)).is_err() {
+                    break;
+                }
+            }
+            stream
+        });
+        Self {
+            index,
+            time_base,
+            jobs,
+            packets,
+            thread,
+        }
+    }
+
+    /// Stop the thread once done with its jobs, getting the stream back
+    fn join(self) -> StemStream {
+        drop(self.jobs);
+        self.thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
 pub struct Inner {
-    path: PathBuf,
     ctx: context::Output,
-    idx_encoders: Vec<(usize, encoder::Audio, resampling::Context, usize)>,
-    overrun: Vec<Vec<f32>>,
+    /// Dropped after `ctx`, removing the file unless flushed successfully
+    output: PartialFile,
+    /// The encoded streams, unless moved to `workers`
+    streams: Vec<StemStream>,
+    /// The encoded streams, once running on their own thread
+    workers: Vec<Worker>,
     metadata: HashMap<Metadata, MetadataValue>,
+    /// Every tag of the source, as a TagLib property map. TagLib maps them to
+    /// MP4 atoms, or freeform `----:com.apple.iTunes:` atoms otherwise.
+    properties: HashMap<String, Vec<String>>,
+    /// Tags of the source which can't be stored in MP4, such as ID3 GEOB frames
+    unsupported_tags: Vec<String>,
     cover: Vec<AttachedPicture>,
+    meters: Option<Vec<Meter>>,
+    gain: f32,
+    replaygain: bool,
+    input_rate: Option<u32>,
+    /// Settings to render the master with, from the sum of the stems
+    mastering_dsp: Option<AtomMasteringDSP>,
+    /// Created with the sample rate of the first buffers
+    dsp: Option<MasteringDSP>,
+    /// Whether each stream gets encoded on its own thread, started with the
+    /// first buffers
+    parallel: bool,
+    /// ISO 639-2 code of every stream
+    language: Option<String>,
+    /// Whether the header has been written, which happens with the first packets
+    started: bool,
+}
+
+impl Inner {
+    /// Write the header, unless already done. It is delayed until the first
+    /// packets so the stream language can still be set.
+    fn start(&mut self) -> Result<(), ffmpeg_next::Error> {
+        if self.started {
+            return Ok(());
+        }
+        for index in 0..self.ctx.nb_streams() as usize {
+            if let Some(language) = self.language.as_ref() {
+                set_stream_metadata(&mut self.ctx, index, "language", language);
+            }
+            // Only the master plays in players unaware of stems
+            let disposition = if index == 0 { Disposition::DEFAULT } else { Disposition::empty() };
+            unsafe {
+                (*self.ctx.stream_mut(index).unwrap().as_mut_ptr()).disposition = disposition.bits();
+            }
+        }
+        // The encoders start their packets at minus their priming delay, which
+        // the muxer turns into an edit list so players drop it.
+        let mut options = Dictionary::new();
+        options.set("use_editlist", "1");
+        self.ctx.write_header_with(options)?;
+        self.started = true;
+        Ok(())
+    }
+
+    /// Number of streams encoded from the stems, the master included unless
+    /// preserved
+    fn encoded_streams(&self) -> usize {
+        self.streams.len() + self.workers.len()
+    }
+
+    /// Run a job for every encoded stream, each on its own thread when
+    /// parallel. The packets are muxed in the same order either way, the
+    /// muxer interleaving them by timestamp.
+    fn encode(&mut self, jobs: Vec<Job>) -> Result<(), Box<dyn std::error::Error>> {
+        if self.parallel && self.workers.is_empty() {
+            self.workers = self.streams.drain(..).map(Worker::spawn).collect();
+        }
+        let mut packets = Vec::new();
+        if self.workers.is_empty() {
+            for (stream, job) in self.streams.iter_mut().zip(jobs) {
+                let encoded = stream.run(job).map_err(|err| err as Box<dyn std::error::Error>)?;
+                let time_base = stream.encoder.time_base();
+                packets.extend(encoded.into_iter().map(|packet| (stream.index, time_base, packet)));
+            }
+        } else {
+            // Every worker gets its job before waiting on any of them, and is
+            // waited on even once one failed, so that no result is left to be
+            // taken as the one of the next job
+            let mut failure: Option<Box<dyn std::error::Error>> = None;
+            for (worker, job) in self.workers.iter().zip(jobs) {
+                if worker.jobs.send(job).is_err() {
+                    failure.get_or_insert_with(|| "an encoder thread has stopped".into());
+                }
+            }
+            for worker in &self.workers {
+                match worker.packets.recv() {
+                    Ok(Ok(encoded)) => {
+                        packets.extend(encoded.into_iter().map(|packet| (worker.index, worker.time_base, packet)))
+                    }
+                    Ok(Err(err)) => {
+                        failure.get_or_insert(err as Box<dyn std::error::Error>);
+                    }
+                    Err(_) => {
+                        failure.get_or_insert_with(|| "an encoder thread has stopped".into());
+                    }
+                }
+            }
+            if let Some(err) = failure {
+                return Err(err);
+            }
+        }
+        for (index, time_base, mut packet) in packets {
+            packet.set_stream(index);
+            packet.rescale_ts(time_base, self.ctx.stream(index).unwrap().time_base());
+            packet.write_interleaved(&mut self.ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Name every stream after its label in the manifest, so players unaware
+    /// of stems show more than a track number. Only read by the muxer when
+    /// writing the trailer.
+    fn set_stream_names(&mut self, manifest: &Atom) {
+        let names = std::iter::once("Master").chain(manifest.stems.iter().map(|stem| stem.name.as_str()));
+        for (index, name) in names.enumerate().take(self.ctx.nb_streams() as usize) {
+            set_stream_metadata(&mut self.ctx, index, "title", name);
+            set_stream_metadata(&mut self.ctx, index, "handler_name", name);
+        }
+    }
+}
+
+/// Add an entry to the metadata of a stream. `StreamMut::set_metadata`
+/// replaces the whole dictionary without freeing the previous one.
+fn set_stream_metadata(ctx: &mut context::Output, index: usize, key: &str, value: &str) {
+    let (Ok(key), Ok(value)) = (CString::new(key), CString::new(value)) else {
+        return;
+    };
+    let mut stream = ctx.stream_mut(index).unwrap();
+    unsafe {
+        av_dict_set(&mut (*stream.as_mut_ptr()).metadata, key.as_ptr(), value.as_ptr(), 0);
+    }
+}
+
+pub struct Original {
+    index: usize,
+    time_base: Rational,
+    /// Encoder delay not already signalled by the codec parameters, in `time_base`
+    delay: i64,
+    /// Offset applied to the original timestamps, known after the first packet
+    offset: Option<i64>,
 }
 
 pub enum NIStem {
-    PreservedMaster(Inner, (usize, Rational)),
+    PreservedMaster(Inner, Original),
     ConsistentStream(Inner)
 }
 
 impl NIStem {
-    pub fn new_with_preserved_original<O: Into<(codec::Parameters, Rational)>, S: Into<(codec::Id, i32)>>(
+    pub fn new_with_preserved_original<O: Into<(codec::Parameters, Rational, Gapless)>, S: Into<(codec::Id, i32)>, E: Into<EncoderOptions>>(
         path: &PathBuf,
         original: O,
         stem: S,
-    ) -> Result<Self, ffmpeg_next::Error> {
+        options: E,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         ffmpeg_next::init()?;
         let original = original.into();
+        check_preservable(original.0.id())?;
         let stem = stem.into();
-        let mut ctx = format::output(&path)?;
+        let options = options.into();
+        options.validate(stem.0)?;
+        check_sample_rate(stem.0, stem.1)?;
+        let output = PartialFile::new(path)?;
+        let mut ctx = format::output(output.path())?;
         unsafe {
             (*ctx.as_mut_ptr()).strict_std_compliance = -2;
         }
+        // Priming signalled in the codec parameters (e.g. Opus pre-skip) is
+        // carried over by the muxer, the rest needs an edit list.
+        let (sample_rate, initial_padding) = unsafe {
+            (
+                (*original.0.as_ptr()).sample_rate,
+                (*original.0.as_ptr()).initial_padding,
+            )
+        };
+        let delay = (original.2.delay as i64 - initial_padding.max(0) as i64).max(0);
+        let delay = if sample_rate > 0 {
+            delay.rescale(Rational::new(1, sample_rate), original.1)
+        } else {
+            0
+        };
         let mut ost = ctx.add_stream(original.0.id())?;
         ost.set_parameters(original.0);
         // We need to set codec_tag to 0 lest we run into incompatible codec tag
@@ -293,44 +970,58 @@ impl NIStem {
         unsafe {
             (*ost.parameters().as_mut_ptr()).codec_tag = 0;
         }
-        let original = (ost.index(), original.1);
+        let original = Original {
+            index: ost.index(),
+            time_base: original.1,
+            delay,
+            offset: None,
+        };
 
         let codec = encoder::find(stem.0).ok_or(ffmpeg_next::Error::InvalidData)?;
-        let mut idx_encoders = Vec::new();
-        let mut formats = codec
-            .audio()?
-            .formats()
-            .ok_or(ffmpeg_next::Error::InvalidData)?;
-        let format = formats.next().ok_or(ffmpeg_next::Error::InvalidData)?;
+        let mut streams = Vec::new();
+        let format = options.sample_format(codec)?;
 
         for _ in 0..4 {
-            let stream = Self::add_stream(&mut ctx, codec, format, stem.1)?;
-            idx_encoders.push(stream);
+            streams.push(Self::add_stream(&mut ctx, codec, format, stem.1, &options)?);
         }
 
-        ctx.write_header()?;
-
         Ok(Self::PreservedMaster(
             Inner {
                 ctx,
-                path: path.clone(),
-                idx_encoders,
-                overrun: vec![Default::default(); 4],
+                output,
+                streams,
+                workers: Vec::new(),
                 metadata: Default::default(),
-                cover: Default::default()
+                properties: Default::default(),
+                unsupported_tags: Default::default(),
+                cover: Default::default(),
+                meters: None,
+                gain: 1.0,
+                replaygain: false,
+                input_rate: None,
+                mastering_dsp: None,
+                dsp: None,
+                parallel: true,
+                language: None,
+                started: false,
             },
             original,
         ))
     }
 
-    pub fn new_with_consistent_streams<S: Into<(codec::Id, i32)>>(
+    pub fn new_with_consistent_streams<S: Into<(codec::Id, i32)>, E: Into<EncoderOptions>>(
         path: &PathBuf,
         stem: S,
-    ) -> Result<Self, ffmpeg_next::Error> {
+        options: E,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         ffmpeg_next::init()?;
         let stem = stem.into();
+        let options = options.into();
+        options.validate(stem.0)?;
+        check_sample_rate(stem.0, stem.1)?;
         // -fflags +genpts ?
-        let mut ctx = format::output(&path)?;
+        let output = PartialFile::new(path)?;
+        let mut ctx = format::output(output.path())?;
         unsafe {
             // Needed for OPUS and FLAC?
             (*ctx.as_mut_ptr()).strict_std_compliance = -2;
@@ -338,28 +1029,32 @@ impl NIStem {
         }
 
         let codec = encoder::find(stem.0).ok_or(ffmpeg_next::Error::InvalidData)?;
-        let mut idx_encoders = Vec::new();
-        let mut formats = codec
-            .audio()?
-            .formats()
-            .ok_or(ffmpeg_next::Error::InvalidData)?;
-        let format = formats.next().ok_or(ffmpeg_next::Error::InvalidData)?;
+        let mut streams = Vec::new();
+        let format = options.sample_format(codec)?;
 
         for _ in 0..5 {
-            let stream = Self::add_stream(&mut ctx, codec, format, stem.1)?;
-            idx_encoders.push(stream);
+            streams.push(Self::add_stream(&mut ctx, codec, format, stem.1, &options)?);
         }
 
-        ctx.write_header()?;
-
         Ok(Self::ConsistentStream(
             Inner {
                 ctx,
-                path: path.clone(),
-                idx_encoders,
-                overrun: vec![Default::default(); 5],
+                output,
+                streams,
+                workers: Vec::new(),
                 metadata: Default::default(),
-                cover: Default::default()
+                properties: Default::default(),
+                unsupported_tags: Default::default(),
+                cover: Default::default(),
+                meters: None,
+                gain: 1.0,
+                replaygain: false,
+                input_rate: None,
+                mastering_dsp: None,
+                dsp: None,
+                parallel: true,
+                language: None,
+                started: false,
             }
         ))
     }
@@ -367,9 +1062,10 @@ impl NIStem {
     fn add_stream(
         ctx: &mut context::Output,
         codec: ffmpeg_next::Codec,
-        format: format::Sample,
+        (format, bits): (format::Sample, usize),
         sample_rate: i32,
-    ) -> Result<(usize, encoder::Audio, resampling::Context, usize), ffmpeg_next::Error> {
+        options: &EncoderOptions,
+    ) -> Result<StemStream, ffmpeg_next::Error> {
         let mut encoder = codec::context::Context::new()
             .encoder()
             .audio()?;
@@ -378,22 +1074,32 @@ impl NIStem {
         encoder.set_rate(sample_rate);
         encoder.set_channel_layout(ChannelLayout::STEREO);
         encoder.set_format(format);
+        encoder.set_time_base((1, sample_rate));
 
+        // The frame size is left to the encoder, e.g. 960 for Opus or 4096
+        // for ALAC
         unsafe {
-            (*encoder.as_mut_ptr()).frame_size = 1024;
+            (*encoder.as_mut_ptr()).bits_per_raw_sample = bits as i32;
         }
+        let private_options = options.apply(&mut encoder);
         let mut ost = ctx.add_stream(codec)?;
-        let encoder = encoder.open_as(codec)?;
+        let encoder = encoder.open_as_with(codec, private_options)?;
         ost.set_parameters(&encoder);
-        let resampler = resampling::Context::get(
-            format::Sample::F32(format::sample::Type::Packed),
+        let converter = converter(
             ffmpeg_next::ChannelLayout::STEREO,
             sample_rate as u32,
-            encoder.format(),
-            encoder.channel_layout(),
-            encoder.rate(),
+            &encoder,
+            bits,
         )?;
-        Ok((ost.index(), encoder, resampler, 0))
+        Ok(StemStream {
+            index: ost.index(),
+            encoder,
+            resampler: None,
+            converter,
+            overrun: Vec::new(),
+            timestamp: 0,
+            encoded: 0,
+        })
     }
 
     pub fn metadata(&self, key: &Metadata) -> Option<&MetadataValue> {
@@ -407,6 +1113,97 @@ impl NIStem {
             NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => inner.metadata.insert(key, value)
         };
     }
+    /// Tags copied from the source which can't be stored in the stem file
+    pub fn unsupported_tags(&self) -> &[String] {
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => &inner.unsupported_tags,
+        }
+    }
+    pub fn cover(&self) -> &[AttachedPicture] {
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => &inner.cover,
+        }
+    }
+    pub fn set_cover(&mut self, cover: Vec<AttachedPicture>) {
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => inner.cover = cover,
+        };
+    }
+    /// Measure the loudness of the master and each stem as they get written.
+    /// When preserving the original, the master has to be fed with
+    /// `analyze_master`.
+    pub fn enable_loudness(&mut self, replaygain: bool) {
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => {
+                // Meters are created with the sample rate of the first buffer
+                inner.meters = Some(Vec::new());
+                inner.replaygain = replaygain;
+            }
+        };
+    }
+    pub fn analyze_master(&mut self, samples: &AudioBuffer) {
+        if let NIStem::PreservedMaster(Inner { meters: Some(meters), .. }, _) = self {
+            if meters.is_empty() {
+                *meters = vec![Meter::new(samples.sample_rate()); 5];
+            }
+            meters[0].add(samples.clone().into_layout(Layout::Interleaved).data());
+        }
+    }
+    /// Loudness of the master followed by the stems, if enabled
+    pub fn loudness(&self) -> Option<Vec<Loudness>> {
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => inner
+                .meters
+                .as_ref()
+                .map(|meters| meters.iter().map(Meter::loudness).collect()),
+        }
+    }
+    /// Render the master as Traktor plays the stems back, through the
+    /// mastering DSP applied on their sum. Nothing changes when neither the
+    /// compressor nor the limiter is enabled.
+    pub fn render_master(&mut self, settings: &AtomMasteringDSP) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            NIStem::ConsistentStream(inner) => {
+                inner.mastering_dsp = MasteringDSP::is_enabled(settings).then(|| settings.clone());
+                Ok(())
+            }
+            NIStem::PreservedMaster(..) => Err("cannot render the master when preserving the original".into()),
+        }
+    }
+    /// Set the ISO 639-2 language code of every stream, e.g. `eng`. It must
+    /// be set before writing any audio.
+    pub fn set_language(&mut self, language: &str) -> Result<(), String> {
+        check_language(language)?;
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => {
+                if inner.started {
+                    return Err("the language must be set before writing audio".to_owned());
+                }
+                inner.language = Some(language.to_owned());
+            }
+        };
+        Ok(())
+    }
+    /// Encode each stream on its own thread, which is the default. The
+    /// output is the same either way. It must be set before writing any
+    /// audio.
+    pub fn set_parallel_encoding(&mut self, parallel: bool) {
+        match self {
+            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => inner.parallel = parallel,
+        };
+    }
+    /// Scale all the encoded streams by a common linear gain. The original
+    /// master cannot be scaled, so this is only available with consistent
+    /// streams.
+    pub fn set_gain(&mut self, gain: f32) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            NIStem::ConsistentStream(inner) => {
+                inner.gain = gain;
+                Ok(())
+            }
+            NIStem::PreservedMaster(..) => Err("cannot apply a gain when preserving the original".into()),
+        }
+    }
     pub fn clone(&mut self, path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
         let tagfile = taglib::File::new(path).map_err(|e| format!("{e:?}"))?;
         let metadata = match tagfile.tag() {
@@ -435,11 +1232,29 @@ impl NIStem {
             Err(_) => HashMap::new(),
         };
         let cover = tagfile.pictures()?;
+        let mut properties = tagfile.properties().unwrap_or_default();
+        properties.retain(|key, _| !SOURCE_ONLY_PROPERTIES.contains(&key.as_str()));
+        let language = properties
+            .get("LANGUAGE")
+            .and_then(|values| values.first())
+            .map(|language| language.to_lowercase())
+            .filter(|language| check_language(language).is_ok());
+        let unsupported_tags = tagfile
+            .complex_property_keys()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|key| !HANDLED_COMPLEX_PROPERTIES.contains(&key.as_str()))
+            .collect();
 
         match self {
             NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => {
                 inner.metadata = metadata;
+                inner.properties = properties;
+                inner.unsupported_tags = unsupported_tags;
                 inner.cover = cover;
+                if !inner.started && language.is_some() {
+                    inner.language = language;
+                }
             }
         };
 
@@ -448,26 +1263,35 @@ impl NIStem {
     pub fn write_preserved(
         &mut self,
         original: impl IntoIterator<Item = Packet>,
-        stems: Vec<Vec<f32>>,
+        stems: Vec<AudioBuffer>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let (inner, original_params) = match self {
             NIStem::PreservedMaster(inner, original) =>Ok((inner, original)),
             _ => Err("cannot write original packet in consistent stem"),
         }?;
+        inner.start()?;
 
         for mut packet in original.into_iter() {
+            // Starting the master at minus the encoder delay lets the muxer write
+            // an edit list, so players drop the priming samples like our decoder
+            // did for the stems.
+            let offset = *original_params.offset.get_or_insert_with(|| {
+                -original_params.delay - packet.dts().or(packet.pts()).unwrap_or(0)
+            });
+            packet.set_pts(packet.pts().map(|pts| pts + offset));
+            packet.set_dts(packet.dts().map(|dts| dts + offset));
             packet.rescale_ts(
-                original_params.1,
-                inner.ctx.stream(original_params.0).unwrap().time_base(),
+                original_params.time_base,
+                inner.ctx.stream(original_params.index).unwrap().time_base(),
             );
-            packet.set_stream(original_params.0);
-            packet.write(&mut inner.ctx)?;
+            packet.set_stream(original_params.index);
+            packet.write_interleaved(&mut inner.ctx)?;
         }
         Self::write_streams(inner, stems)
     }
     pub fn write_consistent(
         &mut self,
-        stems: Vec<Vec<f32>>,
+        stems: Vec<AudioBuffer>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let inner = match self {
             NIStem::ConsistentStream(inner) =>Ok(inner),
@@ -478,144 +1302,254 @@ impl NIStem {
 
     fn write_streams(
         inner: &mut Inner,
-        stems: Vec<Vec<f32>>,
+        stems: Vec<AudioBuffer>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        if stems.len() != inner.idx_encoders.len() {
+        if stems.len() != inner.encoded_streams() {
             return Err("unexpected buffer count".into());
         }
-        for (stream_idx, ((idx, encoder, resampler, timestamp), mut frames)) in inner.idx_encoders.iter_mut().zip(stems).enumerate() {
-            let frame_size = 2 * encoder.frame_size() as usize;
-            if !inner.overrun[stream_idx].is_empty(){
-                frames = {
-                    let mut v = inner.overrun[stream_idx].clone();
-                    v.extend(frames);
-                    v
-                };
-                inner.overrun[stream_idx].clear();
-            }
-            if frames.len() % frame_size != 0 {
-                let new_len = frames.len() - frames.len() % frame_size;
-                inner.overrun[stream_idx].extend_from_slice(&frames[new_len..]);
-                frames.resize(frames.len() - frames.len() % frame_size, 0.0);
-            }
-            for chunk in frames.chunks(frame_size) {
-                let mut frame = Audio::new(
-                    format::Sample::F32(format::sample::Type::Packed),
-                    chunk.len(),
-                    ffmpeg_next::ChannelLayout::STEREO,
-                );
-                frame.set_rate(encoder.rate());
-                frame.set_pts(Some(*timestamp as i64));
-                *timestamp += chunk.len();
-                frame.plane_mut(0).copy_from_slice(chunk);
-                frame.set_samples(chunk.len()/2);
-                let mut resampled = Audio::empty();
-                resampler.run(&frame, &mut resampled)?;
-                encoder.send_frame(&resampled)?;
-                let mut encoded: Packet = Packet::empty();
-                while encoder.receive_packet(&mut encoded).is_ok() {
-                    encoded.set_stream(*idx);
-                    encoded.write(&mut inner.ctx)?;
+        inner.start()?;
+        // The resamplers are set up for the rate of the first buffers, every
+        // following buffer must then match it.
+        let sample_rate = *inner.input_rate.get_or_insert(stems[0].sample_rate());
+        for stem in &stems {
+            stem.ensure(2, sample_rate)?;
+        }
+        if let Some(meters) = inner.meters.as_mut() {
+            if meters.is_empty() {
+                *meters = vec![Meter::new(sample_rate); 5];
+            }
+        }
+        let meter_offset = 5 - inner.encoded_streams();
+        let mut stems: Vec<Vec<f32>> = stems
+            .into_iter()
+            .map(|stem| {
+                let mut frames = stem.into_layout(Layout::Interleaved).into_vec();
+                if inner.gain != 1.0 {
+                    frames.iter_mut().for_each(|sample| *sample *= inner.gain);
                 }
+                frames
+            })
+            .collect();
+        if let Some(settings) = inner.mastering_dsp.as_ref() {
+            let dsp = inner.dsp.get_or_insert_with(|| MasteringDSP::new(settings, sample_rate));
+            let mut master = vec![0f32; stems[1..].iter().map(Vec::len).max().unwrap_or_default()];
+            for stem in &stems[1..] {
+                master.iter_mut().zip(stem).for_each(|(master, sample)| *master += sample);
             }
+            dsp.process(&mut master);
+            stems[0] = master;
         }
-        Ok(())
+        if let Some(meters) = inner.meters.as_mut() {
+            for (stream_idx, frames) in stems.iter().enumerate() {
+                meters[meter_offset + stream_idx].add(frames);
+            }
+        }
+        inner.encode(stems.into_iter().map(|frames| Job::Write(frames, sample_rate)).collect())
     }
 
     pub fn flush(self, manifest: Atom) -> Result<(), Box<dyn std::error::Error>> {
-        let mut inner = match self {
-            NIStem::PreservedMaster(inner, _) | NIStem::ConsistentStream(inner) => inner
+        let (mut inner, preserved) = match self {
+            NIStem::PreservedMaster(inner, _) => (inner, true),
+            NIStem::ConsistentStream(inner) => (inner, false),
         };
-
-        for (stream_idx, (idx, encoder, resampler, timestamp)) in inner.idx_encoders.iter_mut().enumerate() {
-            if !inner.overrun[stream_idx].is_empty(){
-                let chunk = &inner.overrun[stream_idx];
-                let mut frame = Audio::new(
-                    format::Sample::F32(format::sample::Type::Packed),
-                    chunk.len(),
-                    ffmpeg_next::ChannelLayout::STEREO,
-                );
-                frame.set_rate(encoder.rate());
-                frame.set_pts(Some(*timestamp as i64));
-                *timestamp += chunk.len();
-                frame.plane_mut(0).copy_from_slice(chunk);
-                frame.set_samples(chunk.len()/2);
-                let mut resampled = Audio::empty();
-                resampler.run(&frame, &mut resampled)?;
-                encoder.send_frame(&resampled)?;
-                inner.overrun[stream_idx].clear();
-            }
-            if resampler.delay().is_some() {
-                let mut resampled = Audio::empty();
-                resampler.flush(&mut resampled)?;
-                encoder.send_frame(&resampled)?;
-            }
-            encoder.send_eof()?;
-            let mut encoded = Packet::empty();
-            while encoder.receive_packet(&mut encoded).is_ok() {
-                if unsafe { encoded.is_empty() } {
-                    continue;
-                }
-                encoded.set_stream(*idx);
-                encoded.write(&mut inner.ctx)?;
-            }
-        }
+        inner.start()?;
+        inner.encode((0..inner.encoded_streams()).map(|_| Job::Flush).collect())?;
+        let workers = std::mem::take(&mut inner.workers);
+        inner.streams.extend(workers.into_iter().map(Worker::join));
+        // Every stream encodes the same length, so the gapless metadata of the
+        // first one applies to all of them. Players read iTunSMPB as the
+        // gapless metadata of the first track, which is the preserved master
+        // when there is one: its delay differs from the stems, so it only has
+        // its edit list, as the stems do.
+        let itunsmpb = (!preserved && inner.streams[0].encoder.id() == codec::Id::AAC)
+            .then(|| inner.streams[0].itunsmpb());
+        inner.set_stream_names(&manifest);
         inner.ctx.write_trailer()?;
+        drop(inner.ctx);
 
-        let mut file = taglib::File::new(inner.path).map_err(|e| format!("{e:?}"))?;
+        let mut file = taglib::File::new(inner.output.path()).map_err(|e| format!("{e:?}"))?;
 
         file.set_pictures(inner.cover)?;
 
         file.set_stem(Some(serde_json::to_string(&manifest)?))?;
 
-        let mut tag = file.tag().map_err(|e| format!("{e:?}"))?;
-
-        for (key, value) in inner.metadata.iter() {
-            match (key, value) {
-                (Metadata::Title, MetadataValue::String(value)) => {
-                    tag.set_title(value);
-                    Ok(())
-                }
-                (Metadata::Artist, MetadataValue::String(value)) => {
-                    tag.set_artist(value);
-                    Ok(())
-                }
-                (Metadata::Release, MetadataValue::String(value)) => {
-                    tag.set_album(value);
-                    Ok(())
-                }
-                (Metadata::Label, MetadataValue::String(value)) => {
-                    tag.set_comment(value);
-                    Ok(())
-                }
-                (Metadata::TrackNo, MetadataValue::Number(value)) => {
-                    tag.set_track(*value);
-                    Ok(())
-                }
-                (Metadata::Genre, MetadataValue::String(value)) => {
-                    tag.set_genre(value);
-                    Ok(())
-                }
-                _ => Err("unsupported tag format"),
-            }?;
+        let mut properties = file.properties().map_err(|e| format!("{e:?}"))?;
+        properties.extend(inner.properties);
+        if inner.gain != 1.0 {
+            // The gain of the source no longer applies
+            properties.retain(|key, _| !key.starts_with("REPLAYGAIN_"));
+        }
+        if let Some(itunsmpb) = itunsmpb.as_ref() {
+            properties.insert("ITUNSMPB".to_owned(), vec![itunsmpb.clone()]);
         }
+        if let (true, Some(meters)) = (inner.replaygain, inner.meters.as_ref()) {
+            let loudness = meters[0].loudness();
+            properties.insert(
+                "REPLAYGAIN_TRACK_GAIN".to_owned(),
+                vec![format!("{:.2} dB", loudness.replaygain())],
+            );
+            properties.insert(
+                "REPLAYGAIN_TRACK_PEAK".to_owned(),
+                vec![format!("{:.6}", 10f64.powf(loudness.true_peak / 20.0))],
+            );
+        }
+        file.set_properties(&properties).map_err(|e| format!("{e:?}"))?;
+
+        write_tags(&mut file, &inner.metadata)?;
         if !file.save() {
-            Err("unable to save file".into())
-        } else {
-            Ok(())
+            return Err("unable to save file".into());
+        }
+        drop(file);
+        if itunsmpb.is_some() {
+            fix_itunsmpb_name(inner.output.path())?;
+        }
+        inner.output.persist()
+    }
+}
+
+/// The atoms between `start` and `end`, as their type and the range of their
+/// content. Only the headers are read.
+fn atoms(file: &mut std::fs::File, start: u64, end: u64) -> std::io::Result<Vec<([u8; 4], u64, u64)>> {
+    let mut atoms = Vec::new();
+    let mut position = start;
+    while position + 8 <= end {
+        file.seek(SeekFrom::Start(position))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (size, header_size) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            // Extends to the end of its parent
+            0 => (end - position, 8),
+            1 => {
+                let mut size = [0u8; 8];
+                file.read_exact(&mut size)?;
+                (u64::from_be_bytes(size), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_size || position + size > end {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated MP4 atom"));
+        }
+        atoms.push((kind, position + header_size, position + size));
+        position += size;
+    }
+    Ok(atoms)
+}
+
+/// TagLib upper-cases the name of freeform atoms, while readers such as
+/// FFmpeg only recognise `iTunSMPB`. The bindings only expose the property
+/// map, whose keys are always upper case, and FFmpeg's muxer doesn't write
+/// freeform atoms, so the name, of the same length, is fixed in place once
+/// saved, looking for it in `moov/udta/meta/ilst` only.
+fn fix_itunsmpb_name(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut range = (0, file.metadata()?.len());
+    // `meta` has a version and flags before its children
+    for (kind, skip) in [(b"moov", 0), (b"udta", 0), (b"meta", 4), (b"ilst", 0)] {
+        let (_, start, end) = atoms(&mut file, range.0, range.1)?
+            .into_iter()
+            .find(|(atom, ..)| atom == kind)
+            .ok_or("unable to find the iTunes metadata")?;
+        range = (start + skip, end);
+    }
+    for (_, start, end) in atoms(&mut file, range.0, range.1)?.into_iter().filter(|(atom, ..)| atom == b"----") {
+        for (kind, start, end) in atoms(&mut file, start, end)? {
+            // The name follows the version and flags
+            if &kind != b"name" || end - start != 12 {
+                continue;
+            }
+            let mut name = [0u8; 8];
+            file.seek(SeekFrom::Start(start + 4))?;
+            file.read_exact(&mut name)?;
+            if name.eq_ignore_ascii_case(b"iTunSMPB") {
+                file.seek(SeekFrom::Start(start + 4))?;
+                file.write_all(b"iTunSMPB")?;
+                return Ok(());
+            }
         }
     }
+    Err("unable to find the iTunSMPB atom".into())
+}
+
+/// Converts packed float samples to the format of `encoder`, with a
+/// triangular dither when reducing them to integers of `bits` (or the full
+/// format width when 0).
+pub(crate) fn converter(
+    layout: ChannelLayout,
+    rate: u32,
+    encoder: &encoder::Audio,
+    bits: usize,
+) -> Result<resampling::Context, ffmpeg_next::Error> {
+    let mut options = Dictionary::new();
+    if matches!(encoder.format(), format::Sample::I16(_) | format::Sample::I32(_)) {
+        options.set("dither_method", "triangular_hp");
+        if bits > 0 {
+            options.set("output_sample_bits", &bits.to_string());
+        }
+    }
+    resampling::Context::get_with(
+        format::Sample::F32(format::sample::Type::Packed),
+        layout,
+        rate,
+        encoder.format(),
+        encoder.channel_layout(),
+        encoder.rate(),
+        options,
+    )
+}
+
+/// Write the metadata known by stemgen in the main tag of `file`. The file
+/// still needs to be saved.
+pub(crate) fn write_tags(
+    file: &mut taglib::File,
+    metadata: &HashMap<Metadata, MetadataValue>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tag = file.tag().map_err(|e| format!("{e:?}"))?;
+
+    for (key, value) in metadata.iter() {
+        match (key, value) {
+            (Metadata::Title, MetadataValue::String(value)) => {
+                tag.set_title(value);
+                Ok(())
+            }
+            (Metadata::Artist, MetadataValue::String(value)) => {
+                tag.set_artist(value);
+                Ok(())
+            }
+            (Metadata::Release, MetadataValue::String(value)) => {
+                tag.set_album(value);
+                Ok(())
+            }
+            (Metadata::Label, MetadataValue::String(value)) => {
+                tag.set_comment(value);
+                Ok(())
+            }
+            (Metadata::TrackNo, MetadataValue::Number(value)) => {
+                tag.set_track(*value);
+                Ok(())
+            }
+            (Metadata::Genre, MetadataValue::String(value)) => {
+                tag.set_genre(value);
+                Ok(())
+            }
+            _ => Err("unsupported tag format"),
+        }?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
-    use ffmpeg_next::codec;
+    use ffmpeg_next::{codec, encoder, format};
 
     use crate::{
-        nistem::{Atom, Color, NIStem},
-        track::Track,
+        audiofile::{AudioFileWriter, AudioFormat, BitDepth},
+        buffer::{AudioBuffer, Layout},
+        nistem::{check_preservable, check_sample_rate, Atom, Codec, Color, EncoderOptions, NIStem, OpusApplication, SampleRate},
+        reader::NIStemReader,
+        track::{parse_itunsmpb, Gapless, Track},
     };
 
     #[test]
@@ -643,6 +1577,179 @@ mod tests {
         assert_eq!(atom, expected);
     }
 
+    #[test]
+    fn test_encoder_options_validation() {
+        let options = EncoderOptions {
+            bitrate: Some(256_000),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::AAC).is_ok());
+        assert!(options.validate(codec::Id::OPUS).is_ok());
+        assert!(options.validate(codec::Id::FLAC).is_err());
+
+        let options = EncoderOptions {
+            bitrate: Some(1_000),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::AAC).is_err());
+
+        let options = EncoderOptions {
+            quality: Some(1.5),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::AAC).is_ok());
+        assert!(options.validate(codec::Id::OPUS).is_err());
+
+        let options = EncoderOptions {
+            compression_level: Some(8),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::FLAC).is_ok());
+        assert!(options.validate(codec::Id::OPUS).is_ok());
+        assert!(options.validate(codec::Id::ALAC).is_err());
+        assert!(options.validate(codec::Id::AAC).is_err());
+
+        let options = EncoderOptions {
+            vbr: Some(false),
+            application: Some(OpusApplication::LowDelay),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::OPUS).is_ok());
+        assert!(options.validate(codec::Id::AAC).is_err());
+
+        let options = EncoderOptions {
+            bit_depth: Some(BitDepth::Float32),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::FLAC).is_err());
+        assert!(options.validate(codec::Id::ALAC).is_err());
+        assert!(options.validate(codec::Id::AAC).is_err());
+
+        let options = EncoderOptions {
+            bit_depth: Some(BitDepth::Int32),
+            ..Default::default()
+        };
+        assert!(options.validate(codec::Id::FLAC).is_ok());
+        assert!(options.validate(codec::Id::ALAC).is_err());
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        assert_eq!(SampleRate::try_from("96000"), Ok(SampleRate::Hz96000));
+        assert_eq!(SampleRate::try_from("32000"), Ok(SampleRate::Other(32000)));
+        assert!(SampleRate::try_from("1000").is_err());
+        assert!(SampleRate::try_from("fast").is_err());
+        assert_eq!(SampleRate::Hz88200.to_string(), "88200 Hz");
+
+        assert!(check_sample_rate(codec::Id::FLAC, 192000).is_ok());
+        assert!(check_sample_rate(codec::Id::AAC, 96000).is_ok());
+        assert!(check_sample_rate(codec::Id::OPUS, 44100).is_err());
+        assert_eq!(SampleRate::Hz44100.supported_by(codec::Id::OPUS), Ok(SampleRate::Hz48000));
+        assert_eq!(SampleRate::Hz192000.supported_by(codec::Id::AAC), Ok(SampleRate::Hz96000));
+        assert_eq!(SampleRate::Hz192000.supported_by(codec::Id::ALAC), Ok(SampleRate::Hz192000));
+    }
+
+    #[test]
+    fn test_preservable_codecs() {
+        assert!(check_preservable(codec::Id::MP3).is_ok());
+        assert!(check_preservable(codec::Id::ALAC).is_ok());
+        assert!(check_preservable(codec::Id::VORBIS).is_err());
+        assert!(check_preservable(codec::Id::PCM_S16LE).is_err());
+
+        let mut parameters = codec::Parameters::new();
+        unsafe {
+            (*parameters.as_mut_ptr()).codec_type = ffmpeg_next::ffi::AVMediaType::AVMEDIA_TYPE_AUDIO;
+            (*parameters.as_mut_ptr()).codec_id = codec::Id::WMAV2.into();
+        }
+        let output_filename = std::env::temp_dir().join("test_preservable_codecs.stem.mp4");
+        let result = NIStem::new_with_preserved_original(
+            &output_filename,
+            (parameters, ffmpeg_next::Rational::new(1, 44100), Default::default()),
+            (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
+        );
+        assert!(result.is_err());
+        assert!(!output_filename.exists());
+    }
+
+    #[test]
+    fn test_sample_format_from_bit_depth() {
+        let flac = encoder::find(codec::Id::FLAC).unwrap();
+        let alac = encoder::find(codec::Id::ALAC).unwrap();
+        let options = |bit_depth| EncoderOptions {
+            bit_depth,
+            ..Default::default()
+        };
+        assert_eq!(
+            options(Some(BitDepth::Int16)).sample_format(flac).unwrap(),
+            (format::Sample::I16(format::sample::Type::Packed), 0)
+        );
+        assert_eq!(
+            options(Some(BitDepth::Int24)).sample_format(flac).unwrap(),
+            (format::Sample::I32(format::sample::Type::Packed), 24)
+        );
+        assert_eq!(
+            options(Some(BitDepth::Int32)).sample_format(flac).unwrap(),
+            (format::Sample::I32(format::sample::Type::Packed), 32)
+        );
+        assert!(options(Some(BitDepth::Float32)).sample_format(flac).is_err());
+        assert_eq!(
+            options(Some(BitDepth::Int24)).sample_format(alac).unwrap(),
+            (format::Sample::I32(format::sample::Type::Planar), 24)
+        );
+    }
+
+    #[test]
+    fn test_validate_manifest() {
+        assert_eq!(Atom::default().validate(), Ok(()));
+
+        let mut manifest = Atom::default();
+        manifest.version = 2;
+        assert!(manifest.validate().is_err());
+
+        let mut manifest = Atom::default();
+        manifest.stems[2].name = " ".to_owned();
+        assert_eq!(manifest.validate(), Err("stems[2].name must not be empty".to_owned()));
+
+        let mut manifest = Atom::default();
+        manifest.mastering_dsp.compressor.ratio = 0;
+        assert_eq!(
+            manifest.validate(),
+            Err("mastering_dsp.compressor.ratio must be between 1 and 100, got 0".to_owned())
+        );
+
+        let mut manifest = Atom::default();
+        manifest.mastering_dsp.limiter.ceiling = 3;
+        assert!(manifest.validate().is_err());
+    }
+
+    #[test]
+    fn test_manifest_from_file() {
+        let path = std::env::temp_dir().join("test_manifest_from_file.json");
+        let mut manifest = Atom::default();
+        manifest.mastering_dsp.limiter.enabled = true;
+        std::fs::write(&path, manifest.to_json().unwrap()).unwrap();
+        assert_eq!(Atom::from_file(&path).unwrap(), manifest);
+
+        std::fs::write(&path, "{\"version\": 1}").unwrap();
+        assert!(Atom::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_set_mastering_dsp() {
+        let mut manifest = Atom::default();
+        manifest.set_mastering_dsp("limiter.enabled", "true").unwrap();
+        manifest.set_mastering_dsp("compressor.release", "0.5").unwrap();
+        assert!(manifest.mastering_dsp.limiter.enabled);
+        assert_eq!(manifest.mastering_dsp.compressor.release, 0.5);
+
+        assert!(manifest.set_mastering_dsp("limiter", "true").is_err());
+        assert!(manifest.set_mastering_dsp("limiter.unknown", "true").is_err());
+        assert!(manifest.set_mastering_dsp("compressor.ratio", "high").is_err());
+        assert!(manifest.set_mastering_dsp("compressor.ratio", "2.5").is_err());
+    }
+
     #[test]
     fn test_ensure_id3() {
         let output_filename = generate_test_file("test_ensure_id3");
@@ -670,6 +1777,90 @@ mod tests {
         std::fs::remove_file(&output_filename).unwrap();
     }
 
+    #[test]
+    fn test_decoded_duration_matches_input() {
+        for stem_codec in [Codec::AAC, Codec::ALAC, Codec::FLAC, Codec::OPUS] {
+            let id = codec::Id::from(stem_codec);
+            let rate = u32::from(SampleRate::Hz44100.supported_by(id).unwrap());
+            // Not a multiple of any frame size, and written in uneven buffers
+            let frames = rate as usize * 2 + 123;
+            let samples: Vec<f32> = (0..frames)
+                .flat_map(|i| {
+                    let sample = f32::sin(440.0 * i as f32 * 2.0 * std::f32::consts::PI / rate as f32) * 0.5;
+                    [sample, sample]
+                })
+                .collect();
+            let output_filename = std::env::temp_dir().join(format!("test_decoded_duration_{stem_codec}.stem.mp4"));
+            let mut output = NIStem::new_with_consistent_streams(
+                &output_filename,
+                (id, rate as i32),
+                EncoderOptions::default(),
+            )
+            .unwrap();
+            for chunk in [&samples[..2 * 1000], &samples[2 * 1000..]] {
+                let buf = AudioBuffer::from_vec(chunk.to_vec(), 2, rate, Layout::Interleaved).unwrap();
+                output.write_consistent(vec![buf; 5]).unwrap();
+            }
+            output.flush(Atom::default()).unwrap();
+
+            let mut reader = NIStemReader::open(&output_filename).unwrap();
+            let mut decoded = [0usize; 5];
+            loop {
+                let data = reader.read(44100).unwrap();
+                for (decoded, buffer) in decoded.iter_mut().zip(&data) {
+                    assert_eq!(buffer.sample_rate(), rate);
+                    *decoded += buffer.frames();
+                }
+                if data.iter().all(|buffer| buffer.frames() != 44100) {
+                    break;
+                }
+            }
+            assert_eq!(decoded, [frames; 5], "decoded duration of {stem_codec}");
+            std::fs::remove_file(&output_filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_copy_all_tags() {
+        let buf = AudioBuffer::from_vec(vec![0.0; 44100 * 2], 2, 44100, Layout::Interleaved).unwrap();
+        let source = std::env::temp_dir().join("test_copy_all_tags.flac");
+        let mut writer = AudioFileWriter::new(&source, AudioFormat::FLAC, BitDepth::Int16, 2, 44100).unwrap();
+        writer.write(&buf).unwrap();
+        writer.flush().unwrap();
+        let mut file = taglib::File::new(&source).unwrap();
+        let mut properties = file.properties().unwrap();
+        properties.insert("BPM".to_owned(), vec!["128".to_owned()]);
+        properties.insert("INITIALKEY".to_owned(), vec!["Am".to_owned()]);
+        properties.insert("DJ_ANALYSIS".to_owned(), vec!["beatgrid".to_owned()]);
+        properties.insert("ITUNSMPB".to_owned(), vec![" 00000000 00000840".to_owned()]);
+        file.set_properties(&properties).unwrap();
+        assert!(file.save());
+        drop(file);
+
+        let output_filename = std::env::temp_dir().join("test_copy_all_tags.stem.mp4");
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::ALAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        output.clone(&source).unwrap();
+        assert!(output.unsupported_tags().is_empty());
+        output.write_consistent(vec![buf; 5]).unwrap();
+        output.flush(Atom::default()).unwrap();
+
+        let properties = taglib::File::new(&output_filename).unwrap().properties().unwrap();
+        assert_eq!(properties.get("BPM"), Some(&vec!["128".to_owned()]));
+        assert_eq!(properties.get("INITIALKEY"), Some(&vec!["Am".to_owned()]));
+        // Stored as a freeform atom
+        assert_eq!(properties.get("DJ_ANALYSIS"), Some(&vec!["beatgrid".to_owned()]));
+        // Describes the encoding of the source only
+        assert_eq!(properties.get("ITUNSMPB"), None);
+
+        std::fs::remove_file(&source).unwrap();
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+
     #[test]
     fn test_stem_manifest() {
         let output_filename = generate_test_file("test_stem_manifest");
@@ -691,10 +1882,7 @@ mod tests {
         ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Fatal);
         let mut input = Track::new(&"./testdata/Oddchap - Sound 104.mp3".into()).unwrap();
         let mut packets = Vec::with_capacity(2048);
-        let mut buf = vec![0.0f32; 1024 * 1024];
-        // let mut buf = vec![0.0f32; 1024 * 1024 * 100];
-        // assert!(!matches!(input.read(&mut packets, &mut buf), Ok(size) if size == buf.len()));
-        input.read(Some(&mut packets), &mut buf).unwrap();
+        let buf = input.read(Some(&mut packets), 512 * 1024).unwrap();
         let output_filename = std::env::temp_dir().join(format!("{name}.stem.mp4"));
         if output_filename.exists() {
             std::fs::remove_file(&output_filename).unwrap();
@@ -703,6 +1891,7 @@ mod tests {
             &output_filename,
             input.args(),
             (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
         )
         .unwrap();
         output
@@ -716,6 +1905,107 @@ mod tests {
         output_filename
     }
 
+    #[test]
+    fn test_streams_are_aligned() {
+        let output_filename = generate_test_file("test_streams_are_aligned");
+        let mut reader = NIStemReader::open(&output_filename).unwrap();
+        let mut streams = vec![Vec::new(); 5];
+        loop {
+            let data = reader.read(44100).unwrap();
+            for (stream, buffer) in streams.iter_mut().zip(&data) {
+                stream.extend(buffer.data().iter().step_by(2));
+            }
+            if data.iter().all(|buffer| buffer.frames() != 44100) {
+                break;
+            }
+        }
+
+        // The preserved MP3 master and the AAC stems, all decoded from the
+        // same source, start and end on the same sample
+        let correlation = |a: &[f32], b: &[f32], lag: i64| -> f64 {
+            (0..a.len() as i64)
+                .filter_map(|i| Some(a[i as usize] as f64 * *b.get(usize::try_from(i + lag).ok()?)? as f64))
+                .sum()
+        };
+        for stem in &streams[1..] {
+            assert_eq!(stem.len(), streams[0].len());
+            let (lag, _) = (-64..=64)
+                .map(|lag| (lag, correlation(&streams[0][..2 * 44100], stem, lag)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            assert_eq!(lag, 0);
+        }
+
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+
+    #[test]
+    fn test_stream_names() {
+        let output_filename = std::env::temp_dir().join("test_stream_names.stem.mp4");
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        assert!(output.set_language("English").is_err());
+        output.set_language("eng").unwrap();
+        let buf = AudioBuffer::from_vec(vec![0f32; 44100 * 2], 2, 44100, Layout::Interleaved).unwrap();
+        output.write_consistent(vec![buf; 5]).unwrap();
+        assert!(output.set_language("fra").is_err());
+        let mut manifest = Atom::default();
+        manifest.stems[1].name = "Low end".to_owned();
+        output.flush(manifest).unwrap();
+
+        let input = format::input(&output_filename).unwrap();
+        let streams: Vec<_> = input
+            .streams()
+            .map(|stream| {
+                let metadata = stream.metadata();
+                (
+                    metadata.get("handler_name").map(str::to_owned),
+                    metadata.get("language").map(str::to_owned),
+                    stream.disposition().contains(format::stream::Disposition::DEFAULT),
+                )
+            })
+            .collect();
+        let expected = ["Master", "Drums", "Low end", "Other", "Vocals"];
+        assert_eq!(streams.len(), 5);
+        for (index, (stream, name)) in streams.into_iter().zip(expected).enumerate() {
+            assert_eq!(stream, (Some(name.to_owned()), Some("eng".to_owned()), index == 0));
+        }
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_encoding_is_identical() {
+        let buf = Track::new(&"./testdata/Oddchap - Sound 104.mp3".into())
+            .unwrap()
+            .read(None, 512 * 1024)
+            .unwrap();
+        let outputs = [true, false].map(|parallel| {
+            let output_filename =
+                std::env::temp_dir().join(format!("test_parallel_encoding_is_identical_{parallel}.stem.mp4"));
+            let mut output = NIStem::new_with_consistent_streams(
+                &output_filename,
+                (codec::Id::FLAC, 44100),
+                EncoderOptions::default(),
+            )
+            .unwrap();
+            output.set_parallel_encoding(parallel);
+            for chunk in 0..2 {
+                let mut stems = vec![buf.clone(); 5];
+                stems[chunk].data_mut().iter_mut().for_each(|sample| *sample *= 0.5);
+                output.write_consistent(stems).unwrap();
+            }
+            output.flush(Atom::default()).unwrap();
+            let data = std::fs::read(&output_filename).unwrap();
+            std::fs::remove_file(&output_filename).unwrap();
+            data
+        });
+        assert!(outputs[0] == outputs[1]);
+    }
+
     #[test]
     fn test_ensure_coverart() {
         let output_filename = generate_test_file("test_ensure_coverart");
@@ -733,6 +2023,31 @@ mod tests {
         std::fs::remove_file(&output_filename).unwrap();
     }
 
+    #[test]
+    fn test_incomplete_stem_is_removed() {
+        let output_filename = std::env::temp_dir().join("test_incomplete_stem_is_removed.stem.mp4");
+        std::fs::write(&output_filename, b"previous").unwrap();
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        let buf = AudioBuffer::from_vec(vec![0.0; 44100 * 2], 2, 44100, Layout::Interleaved).unwrap();
+        output.write_consistent(vec![buf.clone(), buf.clone(), buf.clone(), buf.clone(), buf]).unwrap();
+        drop(output);
+
+        // The existing file is only replaced on success, and nothing is left behind
+        assert_eq!(std::fs::read(&output_filename).unwrap(), b"previous");
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".test_incomplete_stem_is_removed.stem.mp4"))
+            .count();
+        assert_eq!(leftovers, 0);
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+
     #[test]
     fn test_can_generate_aac() {
         ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Trace);
@@ -742,6 +2057,7 @@ mod tests {
             buf[2*i] = f32::cos(freq * i as f32 * std::f32::consts::PI / 44100_f32) * 0.15;
             buf[2*i + 1] = f32::cos(freq * i as f32 * std::f32::consts::PI / 44100_f32) * 0.15;
         }
+        let buf = AudioBuffer::from_vec(buf, 2, 44100, Layout::Interleaved).unwrap();
 
         let output_filename = std::env::temp_dir().join("test_can_generate_aac.stem.mp4".to_string());
         if output_filename.exists() {
@@ -750,6 +2066,7 @@ mod tests {
         let mut output = NIStem::new_with_consistent_streams(
             &output_filename,
             (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
         )
         .unwrap();
         output
@@ -786,6 +2103,43 @@ mod tests {
         assert_eq!(metadata.genre(), Some("Electro Swing".to_owned()));
     }
 
+    #[test]
+    fn test_itunsmpb() {
+        let output_filename = std::env::temp_dir().join("test_itunsmpb.stem.mp4");
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::AAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        let buf = AudioBuffer::from_vec(vec![0f32; 441000 * 2], 2, 44100, Layout::Interleaved).unwrap();
+        output.write_consistent(vec![buf; 5]).unwrap();
+        output.flush(Atom::default()).unwrap();
+
+        let mut input = format::input(&output_filename).unwrap();
+        let packets = input.packets().filter(|(stream, _)| stream.index() == 0).count() as u32;
+
+        // The padding runs up to the end of the last packet, the ones emitted
+        // when flushing the encoder included
+        let properties = taglib::File::new(&output_filename).unwrap().properties().unwrap();
+        let itunsmpb = properties.get("ITUNSMPB").and_then(|value| parse_itunsmpb(&value[0]));
+        assert_eq!(
+            itunsmpb,
+            Some((Gapless { delay: 1024, padding: packets * 1024 - 1024 - 441000 }, 441000))
+        );
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+
+    #[test]
+    fn test_preserved_master_has_no_itunsmpb() {
+        // iTunSMPB would describe the first track, the preserved MP3 master,
+        // with the delay of the AAC stems
+        let output_filename = generate_test_file("test_preserved_master_has_no_itunsmpb");
+        let properties = taglib::File::new(&output_filename).unwrap().properties().unwrap();
+        assert_eq!(properties.get("ITUNSMPB"), None);
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+
     #[test]
     fn test_can_generate_alac() {
         ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Trace);
@@ -795,6 +2149,7 @@ mod tests {
             buf[2*i] = f32::cos(freq * i as f32 * std::f32::consts::PI / 44100_f32) * 0.15;
             buf[2*i + 1] = f32::cos(freq * i as f32 * std::f32::consts::PI / 44100_f32) * 0.15;
         }
+        let buf = AudioBuffer::from_vec(buf, 2, 44100, Layout::Interleaved).unwrap();
 
         let output_filename = std::env::temp_dir().join("test_can_generate_alac.stem.mp4".to_string());
         if output_filename.exists() {
@@ -803,6 +2158,7 @@ mod tests {
         let mut output = NIStem::new_with_consistent_streams(
             &output_filename,
             (codec::Id::ALAC, 44100),
+            EncoderOptions::default(),
         )
         .unwrap();
         output
@@ -846,6 +2202,7 @@ mod tests {
             buf[2*i] = f32::cos(freq * i as f32 * std::f32::consts::PI / 48000_f32) * 0.15;
             buf[2*i + 1] = f32::cos(freq * i as f32 * std::f32::consts::PI / 48000_f32) * 0.15;
         }
+        let buf = AudioBuffer::from_vec(buf, 2, 48000, Layout::Interleaved).unwrap();
 
         let output_filename = std::env::temp_dir().join("test_can_generate_opus.stem.mp4".to_string());
         if output_filename.exists() {
@@ -854,6 +2211,7 @@ mod tests {
         let mut output = NIStem::new_with_consistent_streams(
             &output_filename,
             (codec::Id::OPUS, 48000),
+            EncoderOptions::default(),
         )
         .unwrap();
         output
@@ -897,6 +2255,7 @@ mod tests {
             buf[2*i] = f32::cos(freq * i as f32 * std::f32::consts::PI / 44100_f32) * 0.15;
             buf[2*i + 1] = f32::cos(freq * i as f32 * std::f32::consts::PI / 44100_f32) * 0.15;
         }
+        let buf = AudioBuffer::from_vec(buf, 2, 44100, Layout::Interleaved).unwrap();
 
         let output_filename = std::env::temp_dir().join("test_can_generate_flac.stem.mp4".to_string());
         if output_filename.exists() {
@@ -905,6 +2264,7 @@ mod tests {
         let mut output = NIStem::new_with_consistent_streams(
             &output_filename,
             (codec::Id::FLAC, 44100),
+            EncoderOptions::default(),
         )
         .unwrap();
         output
@@ -0,0 +1,102 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Temporary files being written, to remove when the process is interrupted
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// A file written next to its destination under a hidden temporary name, and
+/// only moved into place once complete. It is removed when dropped before
+/// being persisted, so a failure never leaves a truncated output behind.
+#[derive(Debug)]
+pub struct PartialFile {
+    path: PathBuf,
+    target: PathBuf,
+    persisted: bool,
+}
+
+impl PartialFile {
+    pub fn new(target: &Path) -> Result<Self, String> {
+        let file_name = target
+            .file_name()
+            .ok_or(format!("{} is not a file path", target.display()))?;
+        // The extension is kept, as FFmpeg and TagLib guess the format from it
+        let path = target.with_file_name(format!(".{}.{}", std::process::id(), file_name.to_string_lossy()));
+        if let Ok(mut pending) = PENDING.lock() {
+            pending.push(path.clone());
+        }
+        Ok(Self {
+            path,
+            target: target.to_path_buf(),
+            persisted: false,
+        })
+    }
+
+    /// Where the content gets written until persisted
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// Move the complete file to its destination, replacing any existing file
+    pub fn persist(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::rename(&self.path, &self.target)
+            .map_err(|e| format!("unable to move the output to {}: {e}", self.target.display()))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        if let Ok(mut pending) = PENDING.lock() {
+            pending.retain(|path| path != &self.path);
+        }
+    }
+}
+
+/// Remove every temporary file still being written. Meant to be called on
+/// interruption, as destructors don't run when the process exits.
+pub fn remove_pending() {
+    if let Ok(pending) = PENDING.lock() {
+        for path in pending.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::partial::PartialFile;
+
+    #[test]
+    fn test_partial_file() {
+        let target = std::env::temp_dir().join("test_partial_file.stem.mp4");
+        let _ = std::fs::remove_file(&target);
+
+        let partial = PartialFile::new(&target).unwrap();
+        assert_eq!(partial.path().parent(), target.parent());
+        assert!(partial.path().to_string_lossy().ends_with(".stem.mp4"));
+        std::fs::write(partial.path(), b"incomplete").unwrap();
+        let path = partial.path().to_path_buf();
+        drop(partial);
+        assert!(!path.exists());
+        assert!(!target.exists());
+
+        let partial = PartialFile::new(&target).unwrap();
+        std::fs::write(partial.path(), b"complete").unwrap();
+        let path = partial.path().to_path_buf();
+        partial.persist().unwrap();
+        assert!(!path.exists());
+        assert_eq!(std::fs::read(&target).unwrap(), b"complete");
+
+        std::fs::remove_file(&target).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use ffmpeg_next::{
+    decoder, format::{self, context}, frame::Audio, media, software::resampling, ChannelLayout
+};
+use taglib::AttachedPicture;
+
+use crate::{
+    buffer::{AudioBuffer, Layout},
+    constant::{Metadata, MetadataValue},
+    nistem::Atom,
+    track::{read_covers, read_tags},
+};
+
+struct Stream {
+    index: usize,
+    decoder: decoder::Audio,
+    resampler: resampling::Context,
+    /// Decoded stereo interleaved samples, not yet returned
+    queue: Vec<f32>,
+}
+
+impl Stream {
+    /// An output frame large enough for `samples` decoded frames converted to
+    /// the output rate, plus those buffered in the resampler
+    fn frame(&self, samples: usize) -> Audio {
+        let (input, output) = (self.resampler.input(), self.resampler.output());
+        let delay = self.resampler.delay().map_or(0, |delay| delay.output.max(0) as usize);
+        let capacity = (samples * output.rate as usize).div_ceil(input.rate.max(1) as usize) + delay + 16;
+        Audio::new(output.format, capacity, output.channel_layout)
+    }
+
+    fn push(&mut self, resampled: &Audio) {
+        self.queue.extend(
+            resampled
+                .plane::<(f32, f32)>(0)
+                .iter()
+                .flat_map(|(left, right)| [*left, *right]),
+        );
+    }
+
+    fn receive(&mut self) -> Result<(), ffmpeg_next::Error> {
+        let mut decoded = Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let mut resampled = self.frame(decoded.samples());
+            self.resampler.run(&decoded, &mut resampled)?;
+            self.push(&resampled);
+        }
+        Ok(())
+    }
+
+    /// Drain the samples still buffered in the resampler, once the decoder
+    /// reached the end of the stream
+    fn flush(&mut self) -> Result<(), ffmpeg_next::Error> {
+        while self.resampler.delay().is_some() {
+            let mut resampled = self.frame(0);
+            self.resampler.flush(&mut resampled)?;
+            if resampled.samples() == 0 {
+                break;
+            }
+            self.push(&resampled);
+        }
+        Ok(())
+    }
+}
+
+/// Reads back the master and the stems of a NI stem file.
+pub struct NIStemReader {
+    path: PathBuf,
+    ctx: context::Input,
+    streams: Vec<Stream>,
+    eof: bool,
+}
+
+impl NIStemReader {
+    pub fn open(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::open_at(path, None)
+    }
+
+    /// Open a stem file, decoding every stream at `sample_rate`
+    pub fn open_with_sample_rate(path: &PathBuf, sample_rate: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::open_at(path, Some(sample_rate))
+    }
+
+    fn open_at(path: &PathBuf, sample_rate: Option<u32>) -> Result<Self, Box<dyn std::error::Error>> {
+        ffmpeg_next::init()?;
+        let ctx = format::input(&path)?;
+
+        let mut streams = Vec::new();
+        for stream in ctx
+            .streams()
+            .filter(|stream| stream.parameters().medium() == media::Type::Audio)
+        {
+            let decoder = ffmpeg_next::codec::context::Context::from_parameters(stream.parameters())?
+                .decoder()
+                .audio()?;
+            let resampler = resampling::Context::get(
+                decoder.format(),
+                decoder.channel_layout(),
+                decoder.rate(),
+                format::Sample::F32(format::sample::Type::Packed),
+                ChannelLayout::STEREO,
+                sample_rate.unwrap_or(decoder.rate()),
+            )?;
+            streams.push(Stream {
+                index: stream.index(),
+                decoder,
+                resampler,
+                queue: Vec::new(),
+            });
+        }
+        if streams.len() != 5 {
+            return Err(format!(
+                "expected 5 audio streams in a stem file, found {}",
+                streams.len()
+            )
+            .into());
+        }
+
+        Ok(Self {
+            path: path.clone(),
+            ctx,
+            streams,
+            eof: false,
+        })
+    }
+
+    /// The stem manifest, if the file has one
+    pub fn manifest(&self) -> Result<Option<Atom>, Box<dyn std::error::Error>> {
+        let file = taglib::File::new(&self.path).map_err(|e| format!("{e:?}"))?;
+        match file.stem()? {
+            Some(manifest) => Ok(Some(serde_json::from_str(&manifest)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn tags(&self) -> HashMap<Metadata, MetadataValue> {
+        read_tags(&self.path)
+    }
+
+    pub fn covers(&self) -> Vec<AttachedPicture> {
+        read_covers(&self.path)
+    }
+
+    /// Channel count and sample rate of the master followed by the stems, as
+    /// stored in the file
+    pub fn formats(&self) -> Vec<(u16, u32)> {
+        self.streams
+            .iter()
+            .map(|stream| (stream.decoder.channels(), stream.decoder.rate()))
+            .collect()
+    }
+
+    /// Duration of the master, in seconds
+    pub fn total(&self) -> i64 {
+        let stream = self.ctx.stream(self.streams[0].index).unwrap();
+        stream.time_base().numerator() as i64 * stream.duration()
+            / stream.time_base().denominator() as i64
+    }
+
+    /// Decode up to `frames` frames of the master followed by the four stems,
+    /// as stereo interleaved samples at their own sample rate, unless opened
+    /// with one. The returned
+    /// buffers are only shorter than requested once the end of the file has
+    /// been reached.
+    pub fn read(&mut self, frames: usize) -> Result<Vec<AudioBuffer>, Box<dyn std::error::Error>> {
+        while !self.eof && self.streams.iter().any(|stream| stream.queue.len() < 2 * frames) {
+            let next = self
+                .ctx
+                .packets()
+                .next()
+                .map(|(stream, packet)| (stream.index(), packet));
+            match next {
+                Some((index, packet)) => {
+                    if let Some(stream) = self.streams.iter_mut().find(|stream| stream.index == index) {
+                        stream.decoder.send_packet(&packet)?;
+                        stream.receive()?;
+                    }
+                }
+                None => {
+                    for stream in self.streams.iter_mut() {
+                        stream.decoder.send_eof()?;
+                        stream.receive()?;
+                        stream.flush()?;
+                    }
+                    self.eof = true;
+                }
+            }
+        }
+        Ok(self
+            .streams
+            .iter_mut()
+            .map(|stream| {
+                let len = stream.queue.len().min(2 * frames);
+                AudioBuffer::from_vec(
+                    stream.queue.drain(..len).collect(),
+                    2,
+                    stream.resampler.output().rate,
+                    Layout::Interleaved,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg_next::codec;
+
+    use crate::{
+        buffer::{AudioBuffer, Layout},
+        nistem::{Atom, EncoderOptions, NIStem},
+        reader::NIStemReader,
+    };
+
+    #[test]
+    fn test_can_read_back_stem() {
+        let mut buf = vec![0.0f32; 44100 * 5 * 2];
+        for i in 0..buf.len() / 2 {
+            buf[2 * i] = f32::sin(440.0 * i as f32 * 2.0 * std::f32::consts::PI / 44100_f32) * 0.5;
+            buf[2 * i + 1] = buf[2 * i];
+        }
+        let buf = AudioBuffer::from_vec(buf, 2, 44100, Layout::Interleaved).unwrap();
+
+        let output_filename = std::env::temp_dir().join("test_can_read_back_stem.stem.mp4");
+        if output_filename.exists() {
+            std::fs::remove_file(&output_filename).unwrap();
+        }
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::ALAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        output.write_consistent(vec![buf.clone(); 5]).unwrap();
+        output.flush(Atom::default()).unwrap();
+
+        let mut reader = NIStemReader::open(&output_filename).unwrap();
+        assert_eq!(reader.manifest().unwrap(), Some(Atom::default()));
+        let mut frames = 0;
+        loop {
+            let data = reader.read(44100).unwrap();
+            assert_eq!(data.len(), 5);
+            assert_eq!(data[0].sample_rate(), 44100);
+            frames += data[0].frames();
+            if data[0].frames() != 44100 {
+                break;
+            }
+        }
+        assert_eq!(frames, buf.frames());
+
+        // Upsampled, including the samples left in the resampler at the end
+        let mut reader = NIStemReader::open_with_sample_rate(&output_filename, 96000).unwrap();
+        let mut frames = 0;
+        loop {
+            let data = reader.read(44100).unwrap();
+            assert_eq!(data[0].sample_rate(), 96000);
+            frames += data[0].frames();
+            if data[0].frames() != 44100 {
+                break;
+            }
+        }
+        let expected = buf.frames() * 96000 / 44100;
+        assert!(frames.abs_diff(expected) <= 16, "{frames} frames instead of {expected}");
+
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+}
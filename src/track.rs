@@ -1,11 +1,73 @@
 use std::{collections::HashMap, path::PathBuf};
 
 use ffmpeg_next::{
-    codec, decoder, ffi::av_rescale_q, format::{self, context}, frame::Audio, media, software::resampling, Packet, Rational
+    codec, decoder, ffi::{av_packet_new_side_data, av_rescale_q, AVPacketSideDataType}, format::{self, context}, frame::Audio, media, packet::{side_data, Mut}, software::resampling, Packet, Rational
 };
 use taglib::AttachedPicture;
 
-use crate::constant::{Metadata, MetadataValue};
+use crate::{audiofile::BitDepth, buffer::{AudioBuffer, Layout}, constant::{Metadata, MetadataValue}};
+
+/// Encoder delay (priming) and padding of a stream, in samples at the
+/// stream's own sample rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Gapless {
+    pub delay: u32,
+    pub padding: u32,
+}
+
+pub(crate) fn parse_itunsmpb(value: &str) -> Option<(Gapless, u64)> {
+    // " 00000000 00000840 000001CA 00000000000CE4B6 ...": the second, third
+    // and fourth fields are the delay, the padding and the original length.
+    let fields = value
+        .split_whitespace()
+        .take(4)
+        .map(|field| u64::from_str_radix(field, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    if fields.len() != 4 {
+        return None;
+    }
+    Some((
+        Gapless {
+            delay: u32::try_from(fields[1]).ok()?,
+            padding: u32::try_from(fields[2]).ok()?,
+        },
+        fields[3],
+    ))
+}
+
+fn skip_samples(packet: &Packet) -> Option<(u32, u32)> {
+    packet
+        .side_data()
+        .find(|data| data.kind() == side_data::Type::SkipSamples)
+        .and_then(|data| {
+            let data = data.data();
+            if data.len() < 8 {
+                return None;
+            }
+            Some((
+                u32::from_le_bytes(data[..4].try_into().unwrap()),
+                u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            ))
+        })
+}
+
+fn set_skip_samples(packet: &mut Packet, start: u32) -> Result<(), ffmpeg_next::Error> {
+    unsafe {
+        let data = av_packet_new_side_data(
+            packet.as_mut_ptr(),
+            AVPacketSideDataType::AV_PKT_DATA_SKIP_SAMPLES,
+            10,
+        );
+        if data.is_null() {
+            return Err(ffmpeg_next::Error::Bug);
+        }
+        let data = std::slice::from_raw_parts_mut(data, 10);
+        data.fill(0);
+        data[..4].copy_from_slice(&start.to_le_bytes());
+    }
+    Ok(())
+}
 
 pub struct Track {
     path: PathBuf,
@@ -13,13 +75,22 @@ pub struct Track {
     index: usize,
     resampler: resampling::context::Context,
     decoder: decoder::Audio,
-    overrun: [f32; 10240],
+    sample_rate: u32,
+    overrun: Vec<f32>,
     overrun_len: usize,
+    gapless: Gapless,
+    pending: Option<Packet>,
+    remaining: Option<usize>,
 }
 
 impl Track {
     pub fn new(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
-        let ctx = format::input(&path)?;
+        Self::new_with_sample_rate(path, 44100)
+    }
+
+    /// Open the track to be read at `sample_rate`
+    pub fn new_with_sample_rate(path: &PathBuf, sample_rate: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut ctx = format::input(&path)?;
 
         // format::context::input::dump(&ctx, 0, Some(path.to_str().ok_or("unable to read path")?));
         let stream = ctx
@@ -31,6 +102,53 @@ impl Track {
         let context_decoder =
             ffmpeg_next::codec::context::Context::from_parameters(stream.parameters())?;
         let decoder = context_decoder.decoder().audio()?;
+        let (initial_padding, trailing_padding) = unsafe {
+            let parameters = stream.parameters();
+            (
+                (*parameters.as_ptr()).initial_padding,
+                (*parameters.as_ptr()).trailing_padding,
+            )
+        };
+
+        let itunsmpb = ctx
+            .metadata()
+            .get("iTunSMPB")
+            .and_then(parse_itunsmpb)
+            .or_else(|| {
+                ctx.stream(index)
+                    .and_then(|stream| stream.metadata().get("iTunSMPB").and_then(parse_itunsmpb))
+            });
+
+        // The demuxer reports the LAME/Xing delay and the edit list priming
+        // as skip samples on the first packet, which the decoder then applies.
+        let mut pending = None;
+        for (stream, packet) in ctx.packets() {
+            if stream.index() == index {
+                pending = Some(packet);
+                break;
+            }
+        }
+
+        let (gapless, remaining) = match (pending.as_mut(), itunsmpb) {
+            (Some(packet), _) if skip_samples(packet).is_some() => {
+                let (delay, padding) = skip_samples(packet).unwrap();
+                (Gapless { delay, padding }, None)
+            }
+            (Some(packet), Some((gapless, length))) => {
+                // iTunSMPB isn't applied by FFmpeg, so we request the decoder to
+                // skip the delay and stop reading after the original length.
+                set_skip_samples(packet, gapless.delay)?;
+                let remaining = length * sample_rate as u64 / decoder.rate() as u64;
+                (gapless, Some(2 * remaining as usize))
+            }
+            _ => (
+                Gapless {
+                    delay: initial_padding.max(0) as u32,
+                    padding: trailing_padding.max(0) as u32,
+                },
+                None,
+            ),
+        };
 
         let resampler = ffmpeg_next::software::resampling::context::Context::get(
             decoder.format(),
@@ -38,7 +156,7 @@ impl Track {
             decoder.rate(),
             format::Sample::F32(format::sample::Type::Packed),
             ffmpeg_next::ChannelLayout::STEREO,
-            44100,
+            sample_rate,
         )?;
 
         Ok(Self {
@@ -47,18 +165,40 @@ impl Track {
             index,
             resampler,
             decoder,
-            overrun: [0f32; 10240],
+            sample_rate,
+            overrun: Vec::new(),
             overrun_len: Default::default(),
+            gapless,
+            pending,
+            remaining,
         })
     }
 
-    pub fn args(&self) -> (codec::Parameters, Rational) {
+    pub fn args(&self) -> (codec::Parameters, Rational, Gapless) {
         let stream = self
             .ctx
             .streams()
             .find(|s| s.index() == self.index)
             .unwrap();
-        (stream.parameters(), stream.time_base())
+        (stream.parameters(), stream.time_base(), self.gapless)
+    }
+
+    /// The encoder delay and padding of the input. The padding may only be
+    /// known once the whole track has been read.
+    pub fn gapless(&self) -> Gapless {
+        self.gapless
+    }
+
+    /// The sample depth of lossless and PCM inputs, `None` for lossy ones
+    pub fn bit_depth(&self) -> Option<BitDepth> {
+        let bits = unsafe { (*self.decoder.as_ptr()).bits_per_raw_sample };
+        match (bits, self.decoder.format()) {
+            (25.., format::Sample::F32(_) | format::Sample::F64(_)) => Some(BitDepth::Float32),
+            (1..=16, _) | (0, format::Sample::U8(_) | format::Sample::I16(_)) => Some(BitDepth::Int16),
+            (17..=24, _) => Some(BitDepth::Int24),
+            (25.., _) | (0, format::Sample::I32(_)) => Some(BitDepth::Int32),
+            _ => None,
+        }
     }
 
     pub fn total(&self) -> i64 {
@@ -75,7 +215,22 @@ impl Track {
 }
 
 impl Track {
+    /// Decode up to `frames` frames, as stereo interleaved samples at the
+    /// sample rate of the track (44100 Hz unless requested otherwise).
+    /// The returned buffer is only shorter than requested once the end of the
+    /// track has been reached.
     pub fn read(
+        &mut self,
+        original_packets: Option<&mut Vec<Packet>>,
+        frames: usize,
+    ) -> Result<AudioBuffer, Box<dyn std::error::Error>> {
+        let mut buf = vec![0f32; 2 * frames];
+        let read = self.read_into(original_packets, &mut buf)?;
+        buf.truncate(read);
+        Ok(AudioBuffer::from_vec(buf, 2, self.sample_rate, Layout::Interleaved)?)
+    }
+
+    fn read_into(
         &mut self,
         mut original_packets: Option<&mut Vec<Packet>>,
         buf: &mut [f32],
@@ -88,6 +243,7 @@ impl Track {
             self.overrun_len = 0;
         } else if self.overrun_len > buf.len() {
             buf.copy_from_slice(&self.overrun[..buf.len()]);
+            self.overrun.drain(..buf.len());
             self.overrun_len -= buf.len();
             return Ok(buf.len());
         }
@@ -95,11 +251,20 @@ impl Track {
 
         let mut process = |mut resampled: Audio, buf: &mut [f32], read: usize| {
             let output = resampled.plane_mut(0);
+            let output = match self.remaining.as_mut() {
+                Some(remaining) => {
+                    let len = output.len().min(*remaining);
+                    *remaining -= len;
+                    &mut output[..len]
+                }
+                None => output,
+            };
 
             if output.len() > buf.len() - read {
                 let (left, right) = output.split_at_mut(buf.len() - read);
                 buf[read..].copy_from_slice(left);
-                self.overrun[..right.len()].copy_from_slice(right);
+                self.overrun.clear();
+                self.overrun.extend_from_slice(right);
                 self.overrun_len = right.len();
                 return buf.len() - read;
             }
@@ -109,10 +274,19 @@ impl Track {
         };
 
         while read < buf.len() {
-            let eof = if let Some((stream, packet)) = packets.next() {
-                if stream.index() != self.index {
+            let next = match self.pending.take() {
+                Some(packet) => Some((self.index, packet)),
+                None => packets.next().map(|(stream, packet)| (stream.index(), packet)),
+            };
+            let eof = if let Some((index, packet)) = next {
+                if index != self.index {
                     continue;
                 }
+                if let Some((_, padding)) = skip_samples(&packet) {
+                    if padding > 0 {
+                        self.gapless.padding = padding;
+                    }
+                }
                 original_packets = if let Some(original_packets) = original_packets {
                     original_packets.push(packet.clone());
                     Some(original_packets)
@@ -130,7 +304,13 @@ impl Track {
 
             let mut decoded = Audio::empty();
             while self.decoder.receive_frame(&mut decoded).is_ok() {
-                let mut resampled = Audio::empty();
+                // Large enough for the converted samples when upsampling
+                let capacity = decoded.samples() * self.sample_rate as usize / decoded.rate().max(1) as usize + 64;
+                let mut resampled = Audio::new(
+                    self.resampler.output().format,
+                    capacity,
+                    self.resampler.output().channel_layout,
+                );
                 self.resampler.run(&decoded, &mut resampled)?;
                 resampled.set_samples(resampled.samples() * decoded.planes()); // FIXME seems to be a bug upstream?
                 // println!("frame {:?}", resampled.pts());
@@ -161,38 +341,97 @@ impl Track {
         Ok(read)
     }
     pub fn tags(&self) -> HashMap<Metadata, MetadataValue> {
-        taglib::File::new(&self.path)
-            .map(|f| {
-                f.tag()
-                    .map(|tags| {
-                        let mut metadata = HashMap::new();
-                        if let Some(value) = tags.title() {
-                            metadata.insert(Metadata::Title, value.into());
-                        }
-                        if let Some(value) = tags.artist() {
-                            metadata.insert(Metadata::Artist, value.into());
-                        }
-                        if let Some(value) = tags.album() {
-                            metadata.insert(Metadata::Release, value.into());
-                        }
-                        if let Some(value) = tags.comment() {
-                            metadata.insert(Metadata::Label, value.into());
-                        }
-                        if let Some(value) = tags.genre() {
-                            metadata.insert(Metadata::Genre, value.into());
-                        }
-                        if let Some(value) = tags.track() {
-                            metadata.insert(Metadata::TrackNo, value.into());
-                        }
-                        metadata
-                    })
-                    .unwrap_or(HashMap::new())
-            })
-            .unwrap_or(HashMap::new())
+        read_tags(&self.path)
     }
     pub fn covers(&self) -> Vec<AttachedPicture> {
-        taglib::File::new(&self.path)
-            .map(|f| f.pictures().unwrap_or(vec![]))
-            .unwrap_or(vec![])
+        read_covers(&self.path)
+    }
+}
+
+pub(crate) fn read_tags(path: &PathBuf) -> HashMap<Metadata, MetadataValue> {
+    taglib::File::new(path)
+        .map(|f| {
+            f.tag()
+                .map(|tags| {
+                    let mut metadata = HashMap::new();
+                    if let Some(value) = tags.title() {
+                        metadata.insert(Metadata::Title, value.into());
+                    }
+                    if let Some(value) = tags.artist() {
+                        metadata.insert(Metadata::Artist, value.into());
+                    }
+                    if let Some(value) = tags.album() {
+                        metadata.insert(Metadata::Release, value.into());
+                    }
+                    if let Some(value) = tags.comment() {
+                        metadata.insert(Metadata::Label, value.into());
+                    }
+                    if let Some(value) = tags.genre() {
+                        metadata.insert(Metadata::Genre, value.into());
+                    }
+                    if let Some(value) = tags.track() {
+                        metadata.insert(Metadata::TrackNo, value.into());
+                    }
+                    metadata
+                })
+                .unwrap_or(HashMap::new())
+        })
+        .unwrap_or(HashMap::new())
+}
+
+pub(crate) fn read_covers(path: &PathBuf) -> Vec<AttachedPicture> {
+    taglib::File::new(path)
+        .map(|f| f.pictures().unwrap_or(vec![]))
+        .unwrap_or(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audiofile::{AudioFileWriter, AudioFormat, BitDepth},
+        buffer::{AudioBuffer, Layout},
+        track::{parse_itunsmpb, Gapless, Track},
+    };
+
+    #[test]
+    fn test_parse_itunsmpb() {
+        let value = " 00000000 00000840 000001CA 00000000000CE4B6 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000";
+        assert_eq!(
+            parse_itunsmpb(value),
+            Some((Gapless { delay: 2112, padding: 458 }, 844982))
+        );
+        assert_eq!(parse_itunsmpb("invalid"), None);
+        assert_eq!(parse_itunsmpb(" 00000000 00000840"), None);
+    }
+
+    #[test]
+    fn test_gapless_from_lame_header() {
+        let input = Track::new(&"./testdata/Oddchap - Sound 104.mp3".into()).unwrap();
+        // 576 samples from the LAME header, plus the 529 samples of decoder delay
+        assert_eq!(input.gapless().delay, 1105);
+    }
+
+    #[test]
+    fn test_read_at_sample_rate() {
+        let mut input = Track::new_with_sample_rate(&"./testdata/Oddchap - Sound 104.mp3".into(), 96000).unwrap();
+        let buf = input.read(None, 96000).unwrap();
+        assert_eq!(buf.sample_rate(), 96000);
+        assert_eq!(buf.frames(), 96000);
+    }
+
+    #[test]
+    fn test_bit_depth() {
+        let input = Track::new(&"./testdata/Oddchap - Sound 104.mp3".into()).unwrap();
+        assert_eq!(input.bit_depth(), None);
+
+        let output_filename = std::env::temp_dir().join("test_bit_depth.flac");
+        let buf = AudioBuffer::from_vec(vec![0.25; 44100 * 2], 2, 44100, Layout::Interleaved).unwrap();
+        let mut output = AudioFileWriter::new(&output_filename, AudioFormat::FLAC, BitDepth::Int24, 2, 44100).unwrap();
+        output.write(&buf).unwrap();
+        output.flush().unwrap();
+
+        let input = Track::new(&output_filename).unwrap();
+        assert_eq!(input.bit_depth(), Some(BitDepth::Int24));
+        std::fs::remove_file(&output_filename).unwrap();
     }
 }
@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{dsp::MasteringDSP, reader::NIStemReader};
+
+/// Default maximum RMS residual, in dBFS
+pub const DEFAULT_THRESHOLD: f64 = -30.0;
+
+/// Streams may differ by an AAC frame and its priming
+const DURATION_TOLERANCE: f64 = 0.05;
+
+const CHUNK: usize = 102400;
+
+fn to_db(value: f64) -> f64 {
+    20.0 * value.max(1e-9).log10()
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StreamReport {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Decoded duration, in seconds
+    pub duration: f64,
+}
+
+/// How well the stems of a file reconstruct its master. When the manifest
+/// enables the mastering DSP, the sum of the stems goes through it first, as
+/// it would when played back.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Verification {
+    pub file: PathBuf,
+    /// The master followed by the stems
+    pub streams: Vec<StreamReport>,
+    /// RMS of the difference between the master and the sum of the stems, in dBFS
+    pub rms_residual: f64,
+    /// Peak of the difference between the master and the sum of the stems, in dBFS
+    pub peak_residual: f64,
+    pub threshold: f64,
+    /// Reasons for the verification to fail, other than the residual
+    pub issues: Vec<String>,
+    pub passed: bool,
+}
+
+impl Verification {
+    pub fn to_json(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Decode the master and the stems of `path`, and compare the master with
+/// the sum of the stems.
+pub fn verify(path: &PathBuf, threshold: f64) -> Result<Verification, Box<dyn std::error::Error>> {
+    let formats = NIStemReader::open(path)?.formats();
+    // Compare the streams at the rate of the stems
+    let rate = formats[1].1;
+    let mut reader = NIStemReader::open_with_sample_rate(path, rate)?;
+    let settings = reader.manifest()?.unwrap_or_default().mastering_dsp;
+    let mut dsp = MasteringDSP::is_enabled(&settings).then(|| MasteringDSP::new(&settings, rate));
+
+    let mut frames = [0usize; 5];
+    let (mut sum_squares, mut peak, mut samples) = (0f64, 0f32, 0usize);
+    loop {
+        let data = reader.read(CHUNK)?;
+        for (frames, buffer) in frames.iter_mut().zip(&data) {
+            *frames += buffer.frames();
+        }
+        let len = data.iter().map(|buffer| buffer.data().len()).min().unwrap_or(0);
+        let mut sum = vec![0f32; len];
+        for stem in &data[1..] {
+            sum.iter_mut().zip(stem.data()).for_each(|(sum, sample)| *sum += sample);
+        }
+        if let Some(dsp) = dsp.as_mut() {
+            dsp.process(&mut sum);
+        }
+        for (master, sum) in data[0].data().iter().zip(&sum) {
+            let residual = master - sum;
+            sum_squares += (residual as f64).powi(2);
+            peak = peak.max(residual.abs());
+        }
+        samples += len;
+        if data.iter().all(|buffer| buffer.frames() != CHUNK) {
+            break;
+        }
+    }
+
+    let streams: Vec<StreamReport> = formats
+        .iter()
+        .zip(frames)
+        .map(|((channels, sample_rate), frames)| StreamReport {
+            channels: *channels,
+            sample_rate: *sample_rate,
+            duration: frames as f64 / rate as f64,
+        })
+        .collect();
+    let mut issues = Vec::new();
+    if streams.iter().any(|stream| stream.channels != streams[0].channels) {
+        issues.push("the streams don't have the same channel layout".to_owned());
+    }
+    let shortest = streams.iter().map(|stream| stream.duration).fold(f64::INFINITY, f64::min);
+    let longest = streams.iter().map(|stream| stream.duration).fold(0f64, f64::max);
+    if longest - shortest > DURATION_TOLERANCE {
+        issues.push(format!("the stream durations differ by {:.3}s", longest - shortest));
+    }
+    if samples == 0 {
+        issues.push("the file has no audio".to_owned());
+    }
+    let rms_residual = to_db((sum_squares / samples.max(1) as f64).sqrt());
+    Ok(Verification {
+        file: path.clone(),
+        streams,
+        rms_residual,
+        peak_residual: to_db(peak as f64),
+        threshold,
+        passed: issues.is_empty() && rms_residual <= threshold,
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ffmpeg_next::codec;
+
+    use crate::{
+        buffer::{AudioBuffer, Layout},
+        nistem::{Atom, EncoderOptions, NIStem},
+        verify::{verify, DEFAULT_THRESHOLD},
+    };
+
+    fn sine(freq: f32, amplitude: f32) -> AudioBuffer {
+        let mut buf = vec![0.0f32; 44100 * 3 * 2];
+        for i in 0..buf.len() / 2 {
+            buf[2 * i] = f32::sin(freq * i as f32 * 2.0 * std::f32::consts::PI / 44100_f32) * amplitude;
+            buf[2 * i + 1] = buf[2 * i];
+        }
+        AudioBuffer::from_vec(buf, 2, 44100, Layout::Interleaved).unwrap()
+    }
+
+    fn write(name: &str, master: AudioBuffer) -> std::path::PathBuf {
+        let output_filename = std::env::temp_dir().join(format!("{name}.stem.mp4"));
+        let mut output = NIStem::new_with_consistent_streams(
+            &output_filename,
+            (codec::Id::ALAC, 44100),
+            EncoderOptions::default(),
+        )
+        .unwrap();
+        let stems = [220.0, 330.0, 440.0, 550.0].map(|freq| sine(freq, 0.1));
+        output.write_consistent([vec![master], stems.to_vec()].concat()).unwrap();
+        output.flush(Atom::default()).unwrap();
+        output_filename
+    }
+
+    #[test]
+    fn test_verify_residual() {
+        let mut master = vec![0f32; 44100 * 3 * 2];
+        for stem in [220.0, 330.0, 440.0, 550.0].map(|freq| sine(freq, 0.1)) {
+            master.iter_mut().zip(stem.data()).for_each(|(master, sample)| *master += sample);
+        }
+        let master = AudioBuffer::from_vec(master, 2, 44100, Layout::Interleaved).unwrap();
+        let output_filename = write("test_verify_residual", master);
+        let verification = verify(&output_filename, DEFAULT_THRESHOLD).unwrap();
+        assert!(verification.passed, "{verification:?}");
+        assert_eq!(verification.streams.len(), 5);
+        assert!((verification.streams[0].duration - 3.0).abs() < 0.01);
+        // ALAC is lossless, only the 16 bits quantization remains
+        assert!(verification.rms_residual < -80.0, "{verification:?}");
+        std::fs::remove_file(&output_filename).unwrap();
+
+        // A master unrelated to the stems
+        let output_filename = write("test_verify_residual_mismatch", sine(1000.0, 0.5));
+        let verification = verify(&output_filename, DEFAULT_THRESHOLD).unwrap();
+        assert!(!verification.passed);
+        assert!(verification.issues.is_empty());
+        assert!(verification.peak_residual > -6.0, "{verification:?}");
+        std::fs::remove_file(&output_filename).unwrap();
+    }
+}